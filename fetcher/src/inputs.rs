@@ -69,7 +69,7 @@ struct Input {
 }
 
 pub fn stream(input: File) -> impl Stream<Item = (Source, Arc<Option<Checksum>>)> + Send + Unpin {
-    FramedRead::new(input, Inputs::default())
+    FramedRead::new(input, Inputs)
         .filter_map(|result| async move {
             match result {
                 Ok(input) => {
@@ -3,7 +3,7 @@
 
 use crate::execute;
 
-use async_fetcher::{checksum_stream, Checksum, FetchEvent};
+use async_fetcher::{checksum_stream, Checksum, FetchEvent, ProgressTracker};
 use futures::prelude::*;
 use pbr::{MultiBar, Pipe, ProgressBar, Units};
 use std::{
@@ -29,22 +29,25 @@ pub async fn run(
         let progress = Arc::clone(&progress);
 
         async move {
+            let mut tracker = ProgressTracker::default();
             let mut state = HashMap::<Arc<Path>, ProgressBar<Pipe>>::new();
 
             while let Some((dest, _checksum, event)) = erx.recv().await {
+                tracker.update(&dest, &event);
+
                 match event {
-                    FetchEvent::Progress(written) => {
-                        if let Some(bar) = state.get_mut(&dest) {
-                            bar.add(written as u64);
+                    FetchEvent::Progress(_) => {
+                        if let (Some(bar), Some(progress)) =
+                            (state.get_mut(&dest), tracker.file(&dest))
+                        {
+                            bar.set(progress.fetched);
                         }
                     }
 
                     FetchEvent::ContentLength(total) => {
                         state
                             .entry(dest.clone())
-                            .and_modify(|bar| {
-                                bar.set(total);
-                            })
+                            .and_modify(|bar| bar.total = total)
                             .or_insert_with(|| {
                                 let mut bar = progress.create_bar(total);
                                 bar.set_units(Units::Bytes);
@@ -54,11 +57,21 @@ pub async fn run(
                     }
 
                     FetchEvent::Fetched => {
+                        tracker.remove(&dest);
+
                         if let Some(mut bar) = state.remove(&dest) {
                             bar.finish_print(&fomat!("Fetched "(dest.display())));
                         }
                     }
 
+                    FetchEvent::Failed(_) => {
+                        tracker.remove(&dest);
+
+                        if let Some(mut bar) = state.remove(&dest) {
+                            bar.finish_print(&fomat!("Failed "(dest.display())));
+                        }
+                    }
+
                     FetchEvent::Retrying => {
                         if let Some(mut bar) = state.remove(&dest) {
                             bar.finish_print(&fomat!("Retrying "(dest.display())));
@@ -110,12 +123,12 @@ pub async fn run(
     let progress_ticker = async {
         while !complete.load(Ordering::SeqCst) {
             eprintln!("update");
-            let _ = progress.listen();
+            progress.listen();
             tokio::time::sleep(Duration::from_millis(1000)).await;
         }
     };
 
     join!(events, progress_ticker);
 
-    let _ = progress.listen();
+    progress.listen();
 }
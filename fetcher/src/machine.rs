@@ -3,11 +3,10 @@
 
 use crate::execute;
 
-use async_fetcher::{checksum_stream, Checksum, FetchEvent};
+use async_fetcher::{checksum_stream, Checksum, FetchEvent, ProgressTracker};
 use futures::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
     io::{self, Write},
     path::Path,
     sync::Arc,
@@ -23,44 +22,78 @@ pub async fn run(
     // Handles all callback events from the fetcher
     let events_tx_ = events_tx.clone();
     let fetch_events = async move {
-        let mut state = HashMap::<Arc<Path>, (u64, u64)>::new();
+        let mut tracker = ProgressTracker::default();
         while let Some((dest, _checksum, event)) = erx.recv().await {
+            tracker.update(&dest, &event);
+
             let event = match event {
-                FetchEvent::Progress(written) => {
-                    if let Some(progress) = state.get_mut(&dest) {
-                        progress.0 += written as u64;
-                        Output(
-                            fomat!((dest.display())),
-                            OutputEvent::Progress(progress.0, progress.1),
-                        )
-                    } else {
-                        continue;
-                    }
-                }
+                FetchEvent::Progress(_) => match tracker.file(&dest) {
+                    Some(progress) => Output(
+                        fomat!((dest.display())),
+                        OutputEvent::Progress(progress.fetched, progress.length.unwrap_or(0)),
+                    ),
+                    None => continue,
+                },
 
                 FetchEvent::ContentLength(length) => {
-                    state
-                        .entry(dest.clone())
-                        .and_modify(|bar| bar.1 = length)
-                        .or_insert((0, length));
-
                     Output(fomat!((dest.display())), OutputEvent::Length(length))
                 }
 
-                FetchEvent::Fetching => {
-                    state.insert(dest.clone(), (0, 0));
-                    Output(fomat!((dest.display())), OutputEvent::Fetching)
-                }
+                FetchEvent::Fetching => Output(fomat!((dest.display())), OutputEvent::Fetching),
 
                 FetchEvent::Fetched => {
-                    state.remove(&dest);
+                    tracker.remove(&dest);
                     Output(fomat!((dest.display())), OutputEvent::Fetched)
                 }
 
-                FetchEvent::Retrying => {
-                    state.remove(&dest);
-                    Output(fomat!((dest.display())), OutputEvent::Retrying)
+                FetchEvent::Retrying => Output(fomat!((dest.display())), OutputEvent::Retrying),
+
+                FetchEvent::RetryAfter(delay) => Output(
+                    fomat!((dest.display())),
+                    OutputEvent::RetryAfter(delay.as_secs()),
+                ),
+
+                FetchEvent::Mirror(uri) => {
+                    Output(fomat!((dest.display())), OutputEvent::Mirror(uri.into()))
+                }
+
+                FetchEvent::MirrorSwitched(from, to) => Output(
+                    fomat!((dest.display())),
+                    OutputEvent::MirrorSwitched(from.into(), to.into()),
+                ),
+
+                FetchEvent::PartStarted(range, uri) => Output(
+                    fomat!((dest.display())),
+                    OutputEvent::PartStarted(range.start, range.end, uri.into()),
+                ),
+
+                FetchEvent::PartFinished(range, uri) => Output(
+                    fomat!((dest.display())),
+                    OutputEvent::PartFinished(range.start, range.end, uri.into()),
+                ),
+
+                FetchEvent::AlreadyFetched => {
+                    Output(fomat!((dest.display())), OutputEvent::AlreadyFetched)
                 }
+
+                FetchEvent::Resumed(offset) => {
+                    Output(fomat!((dest.display())), OutputEvent::Resumed(offset))
+                }
+
+                FetchEvent::RetryScheduled(reason, delay, attempt) => Output(
+                    fomat!((dest.display())),
+                    OutputEvent::RetryScheduled(reason.into(), delay.as_secs_f64(), attempt),
+                ),
+
+                FetchEvent::Failed(why) => {
+                    epintln!((dest.display()) " failed to fetch: " (why));
+                    tracker.remove(&dest);
+                    Output(fomat!((dest.display())), OutputEvent::Failed)
+                }
+
+                FetchEvent::Paused => Output(fomat!((dest.display())), OutputEvent::Paused),
+
+                FetchEvent::Unpaused => Output(fomat!((dest.display())), OutputEvent::Unpaused),
             };
 
             if events_tx_.send(event).await.is_err() {
@@ -77,11 +110,8 @@ pub async fn run(
             let event = match result {
                 Ok(false) => None,
                 Ok(true) => Some(Output(fomat!((dest.display())), OutputEvent::Validating)),
-                Err(why) => {
-                    epintln!((dest.display()) " failed to validate: " [why]);
-
-                    Some(Output(fomat!((dest.display())), OutputEvent::Failed))
-                }
+                // Failed fetches are reported by the fetcher's events.
+                Err(_) => None,
             };
 
             if let Some(event) = event {
@@ -159,8 +189,17 @@ pub enum OutputEvent {
     Fetching,
     Invalid,
     Length(u64),
+    Mirror(String),
+    MirrorSwitched(String, String),
+    PartFinished(u64, u64, String),
+    PartStarted(u64, u64, String),
+    Paused,
     Progress(u64, u64),
+    Resumed(u64),
     Retrying,
+    RetryAfter(u64),
+    RetryScheduled(String, f64, u16),
+    Unpaused,
     Validated,
     Validating,
 }
@@ -86,8 +86,8 @@ async fn fetcher_stream<
 
     while let Some((dest, checksum, result)) = fetcher.next().await {
         match result {
-            Ok(()) => {
-                let _ = result_sender.send((dest.clone(), Ok(true)));
+            Ok(_) => {
+                let _ = result_sender.send((dest.clone(), Ok(true))).await;
                 if let Some(checksum) = checksum.as_ref() {
                     let _ = checksum_sender.send((dest, checksum.clone())).await;
                 }
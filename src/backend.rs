@@ -0,0 +1,114 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::Error;
+use futures::{future::BoxFuture, io::AsyncRead};
+use http::{Request, Response, Uri};
+
+#[cfg(feature = "isahc")]
+use isahc::HttpClient as IsahcClient;
+#[cfg(feature = "reqwest")]
+use reqwest::Client as ReqwestClient;
+
+/// The body of a response returned by a `HttpBackend`.
+pub type Body = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The URI which a response was received from, after following redirects.
+///
+/// Inserted into the extensions of a response by backends which follow redirects.
+#[derive(Clone, Debug)]
+pub struct EffectiveUri(pub Uri);
+
+/// An HTTP client which the `Fetcher` can use to send its requests.
+///
+/// The fetcher only issues `HEAD` and `GET` requests, optionally with a `Range`
+/// header. Responses with an error status should be returned as-is, rather than
+/// as an `Err`, so that the fetcher can decide how to handle them. Backends which
+/// follow redirects should insert an `EffectiveUri` into the extensions of the
+/// response.
+pub trait HttpBackend: Send + Sync + 'static {
+    /// Sends a request and returns the response once its headers are received.
+    fn send_request(&self, request: Request<()>) -> BoxFuture<'_, Result<Response<Body>, Error>>;
+}
+
+impl<B: HttpBackend> HttpBackend for std::sync::Arc<B> {
+    fn send_request(&self, request: Request<()>) -> BoxFuture<'_, Result<Response<Body>, Error>> {
+        (**self).send_request(request)
+    }
+}
+
+/// The underlying Client used for the Fetcher
+#[cfg(any(feature = "isahc", feature = "reqwest"))]
+pub enum Client {
+    #[cfg(feature = "isahc")]
+    Isahc(IsahcClient),
+    #[cfg(feature = "reqwest")]
+    Reqwest(ReqwestClient),
+}
+
+#[cfg(any(feature = "isahc", feature = "reqwest"))]
+impl HttpBackend for Client {
+    fn send_request(&self, request: Request<()>) -> BoxFuture<'_, Result<Response<Body>, Error>> {
+        match self {
+            #[cfg(feature = "isahc")]
+            Client::Isahc(client) => client.send_request(request),
+            #[cfg(feature = "reqwest")]
+            Client::Reqwest(client) => client.send_request(request),
+        }
+    }
+}
+
+#[cfg(feature = "isahc")]
+impl HttpBackend for IsahcClient {
+    fn send_request(&self, request: Request<()>) -> BoxFuture<'_, Result<Response<Body>, Error>> {
+        Box::pin(async move {
+            let mut response = self.send_async(request).await?;
+
+            if let Some(uri) = isahc::ResponseExt::effective_uri(&response).cloned() {
+                response.extensions_mut().insert(EffectiveUri(uri));
+            }
+
+            Ok(response.map(|body| Box::new(body) as Body))
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpBackend for ReqwestClient {
+    fn send_request(&self, request: Request<()>) -> BoxFuture<'_, Result<Response<Body>, Error>> {
+        use futures::{StreamExt, TryStreamExt};
+
+        let (parts, ()) = request.into_parts();
+
+        let request = self
+            .request(parts.method, parts.uri.to_string())
+            .headers(parts.headers);
+
+        Box::pin(async move {
+            let response = request.send().await?;
+
+            let mut builder = Response::builder()
+                .status(response.status())
+                .version(response.version());
+
+            if let Some(headers) = builder.headers_mut() {
+                *headers = response.headers().clone();
+            }
+
+            if let Ok(uri) = response.url().as_str().parse() {
+                builder = builder.extension(EffectiveUri(uri));
+            }
+
+            let body = response
+                .bytes_stream()
+                .map(|result| {
+                    result.map_err(|why| std::io::Error::new(std::io::ErrorKind::Other, why))
+                })
+                .into_async_read();
+
+            Ok(builder
+                .body(Box::new(body) as Body)
+                .expect("failed to build response"))
+        })
+    }
+}
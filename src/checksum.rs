@@ -6,7 +6,7 @@ use hex::FromHex;
 use md5::Md5;
 use serde::Deserialize;
 use sha2::Sha256;
-use std::{convert::TryFrom, io};
+use std::{convert::TryFrom, fs::File, io, path::Path, sync::Arc};
 
 /// A checksum of a `Source` as a fixed-sized byte array.
 #[derive(Debug, Clone)]
@@ -38,7 +38,7 @@ pub enum SumStrBuf {
 }
 
 impl SumStrBuf {
-    pub fn as_ref(&self) -> SumStr {
+    pub fn as_ref(&self) -> SumStr<'_> {
         match self {
             SumStrBuf::Md5(string) => SumStr::Md5(string.as_str()),
             SumStrBuf::Sha256(string) => SumStr::Sha256(string.as_str()),
@@ -72,6 +72,83 @@ impl Checksum {
             Checksum::Sha256(sum) => checksum::<Sha256, F>(reader, buffer, sum),
         }
     }
+
+    /// Validates a file on disk, reading it from a blocking task.
+    pub(crate) async fn validate_file(&self, path: Arc<Path>) -> Result<(), ChecksumError> {
+        let checksum = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut buffer = vec![0u8; 8 * 1024];
+            checksum.validate(File::open(&*path)?, &mut buffer)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Begins computing the checksum of a file as it is being fetched.
+    pub(crate) fn hasher(&self) -> ChecksumHasher {
+        let state = match self {
+            Checksum::Md5(_) => HasherState::Md5(Md5::new()),
+            Checksum::Sha256(_) => HasherState::Sha256(Sha256::new()),
+        };
+
+        ChecksumHasher {
+            expected: self.clone(),
+            state,
+        }
+    }
+}
+
+/// Computes the checksum of a file from the bytes written to it, in order.
+pub(crate) struct ChecksumHasher {
+    expected: Checksum,
+    state: HasherState,
+}
+
+enum HasherState {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            HasherState::Md5(hasher) => hasher.update(data),
+            HasherState::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Hashes every byte that can be read from `reader`.
+    pub fn update_from<F: io::Read>(&mut self, mut reader: F, buffer: &mut [u8]) -> io::Result<()> {
+        loop {
+            let read = reader.read(buffer)?;
+
+            if read == 0 {
+                return Ok(());
+            }
+
+            self.update(&buffer[..read]);
+        }
+    }
+
+    /// Compares the checksum of the bytes hashed to the expected checksum.
+    pub fn finish(self) -> Result<(), ChecksumError> {
+        let (expected, result) = match (self.expected, self.state) {
+            (Checksum::Md5(expected), HasherState::Md5(hasher)) => {
+                (hex::encode(expected), hex::encode(hasher.finalize()))
+            }
+            (Checksum::Sha256(expected), HasherState::Sha256(hasher)) => {
+                (hex::encode(expected), hex::encode(hasher.finalize()))
+            }
+            _ => unreachable!("hasher does not match its checksum"),
+        };
+
+        if expected == result {
+            Ok(())
+        } else {
+            Err(ChecksumError::Invalid(expected, result))
+        }
+    }
 }
 
 pub(crate) fn checksum<D: Digest, F: io::Read>(
@@ -10,11 +10,16 @@ use std::{path::Path, sync::Arc};
 ///
 /// The caller can choose to distribute these futures across a thread pool.
 ///
-/// ```
+/// ```no_run
+/// # use async_fetcher::{checksum_stream, Checksum};
+/// # use futures::prelude::*;
+/// # use std::{path::Path, sync::Arc};
+/// # async fn example(checksums: impl Stream<Item = (Arc<Path>, Checksum)> + Send + Unpin + 'static) {
 /// let mut stream = checksum_stream(checksums).map(tokio::spawn).buffered(8);
-/// while let Some((path, result)) = stream.next().await {
+/// while let Some(Ok((path, result))) = stream.next().await {
 ///     eprintln!("{:?} checksum result: {:?}", path, result);
 /// }
+/// # }
 /// ```
 pub fn checksum_stream<I: Stream<Item = (Arc<Path>, Checksum)> + Send + Unpin + 'static>(
     inputs: I,
@@ -42,7 +47,7 @@ pub fn validate_checksum(
     dest: &Path,
     checksum: &Checksum,
 ) -> Result<(), ChecksumError> {
-    let error = match std::fs::File::open(&*dest) {
+    let error = match std::fs::File::open(dest) {
         Ok(file) => match checksum.validate(file, buf) {
             Ok(()) => return Ok(()),
             Err(why) => why,
@@ -50,6 +55,6 @@ pub fn validate_checksum(
         Err(why) => ChecksumError::from(why),
     };
 
-    let _ = std::fs::remove_file(&*dest);
+    let _ = std::fs::remove_file(dest);
     Err(error)
 }
@@ -1,23 +1,41 @@
 // Copyright 2021-2022 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
-use crate::Error;
+use crate::checksum::ChecksumHasher;
+use crate::{Checksum, Error};
 
 use async_shutdown::Shutdown;
 use futures::{Stream, StreamExt};
 use std::fs::{self, File};
-use std::io::copy;
+use std::io::{copy, Read, Seek, SeekFrom, Write};
 use std::{path::Path, sync::Arc};
 
 /// Accepts a stream of future file `parts` and concatenates them into the `dest` file.
-pub async fn concatenator<P: 'static>(
+pub async fn concatenator<P>(
+    dest: File,
+    parts: P,
+    path: Arc<Path>,
+    shutdown: Shutdown,
+) -> Result<(), Error>
+where
+    P: Stream<Item = Result<(Arc<Path>, File), Error>> + Send + Unpin + 'static,
+{
+    concatenator_checked(dest, parts, path, shutdown, None).await
+}
+
+/// Concatenates `parts` into the `dest` file like `concatenator`.
+///
+/// If a `checksum` is given, the bytes already in `dest` and those of each part are
+/// hashed as they are concatenated, and the result is validated against it.
+pub(crate) async fn concatenator_checked<P>(
     mut dest: File,
     mut parts: P,
     _path: Arc<Path>,
     shutdown: Shutdown,
+    checksum: Option<Checksum>,
 ) -> Result<(), Error>
 where
-    P: Stream<Item = Result<(Arc<Path>, File), Error>> + Send + Unpin,
+    P: Stream<Item = Result<(Arc<Path>, File), Error>> + Send + Unpin + 'static,
 {
     let main = async move {
         let _token = match shutdown.delay_shutdown_token() {
@@ -25,15 +43,34 @@ where
             Err(_) => return Err(Error::Canceled),
         };
 
+        let mut hasher = checksum.as_ref().map(Checksum::hasher);
+        let mut buffer = vec![0u8; 8 * 1024];
+
         let task = async {
+            if let Some(hasher) = hasher.as_mut() {
+                dest.seek(SeekFrom::Start(0)).map_err(Error::Concatenate)?;
+                hasher
+                    .update_from(&mut dest, &mut buffer)
+                    .map_err(|why| Error::Checksum(why.into()))?;
+            }
+
             while let Some(task_result) = parts.next().await {
                 crate::utils::shutdown_check(&shutdown)?;
 
                 let (source, mut source_file) = task_result?;
-                concatenate(&mut dest, source, &mut source_file)?;
+                concatenate(
+                    &mut dest,
+                    source,
+                    &mut source_file,
+                    &mut hasher,
+                    &mut buffer,
+                )?;
             }
 
-            Ok(())
+            match hasher {
+                Some(hasher) => hasher.finish().map_err(Error::Checksum),
+                None => Ok(()),
+            }
         };
 
         let result = task.await;
@@ -51,8 +88,26 @@ fn concatenate(
     concatenated_file: &mut File,
     part_path: Arc<Path>,
     part_file: &mut File,
+    hasher: &mut Option<ChecksumHasher>,
+    buffer: &mut [u8],
 ) -> Result<(), Error> {
-    copy(part_file, concatenated_file).map_err(Error::Concatenate)?;
+    match hasher {
+        Some(hasher) => loop {
+            let read = part_file.read(buffer).map_err(Error::Concatenate)?;
+
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+            concatenated_file
+                .write_all(&buffer[..read])
+                .map_err(Error::Concatenate)?;
+        },
+        None => {
+            copy(part_file, concatenated_file).map_err(Error::Concatenate)?;
+        }
+    }
 
     if let Err(why) = fs::remove_file(&*part_path) {
         error!("failed to remove part file ({:?}): {}", part_path, why);
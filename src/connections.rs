@@ -0,0 +1,164 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::mirrors::host_of;
+use crate::Body;
+use futures::io::AsyncRead;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits the number of concurrent connections to each host.
+///
+/// Connections are counted from the moment a request is sent until its response body
+/// is dropped, across every source fetched by a `Fetcher`.
+#[derive(Default)]
+pub(crate) struct HostConnections {
+    hosts: Mutex<HashMap<Box<str>, Arc<Semaphore>>>,
+}
+
+impl HostConnections {
+    /// Waits until a connection to the host serving `uri` may be opened.
+    pub async fn acquire(&self, uri: &str, limit: usize) -> OwnedSemaphorePermit {
+        let limit = limit.clamp(1, Semaphore::MAX_PERMITS);
+
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host_of(uri).into())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("host semaphore closed")
+    }
+}
+
+/// A response body which holds its host's connection permit until it is dropped.
+pub(crate) struct PermitBody {
+    pub body: Body,
+    pub _permit: OwnedSemaphorePermit,
+}
+
+impl AsyncRead for PermitBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::support::{self, block_on, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use crate::{ErrorClass, RetryDecision, RetryPolicy, Source};
+    use futures::{future, StreamExt};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn uri(name: &str) -> String {
+        ["http://mirror/", name].concat()
+    }
+
+    fn source(dir: &Path, name: &str) -> Source {
+        Source::builder(Arc::from(dir.join(name)), uri(name).into()).build()
+    }
+
+    #[test]
+    fn connections_to_a_host_are_limited() {
+        let backend = Arc::new(MockBackend::default());
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["a", "b", "c", "d"];
+
+        // Each body stalls, so that every fetch wants a connection at the same time.
+        for name in &names {
+            backend.serve(&uri(name), MockFile::new(contents(4096)));
+            let stall = Fault::StallBody {
+                after: 1000,
+                duration: Duration::from_millis(20),
+            };
+            backend.fault(&uri(name), Some(http::Method::GET), stall);
+        }
+
+        let fetcher = support::fetcher(&backend)
+            .max_connections_per_host(2)
+            .build();
+
+        let inputs = names
+            .iter()
+            .map(|name| (source(dir.path(), name), Arc::new(())));
+        let fetches = fetcher.stream_from(futures::stream::iter(inputs.collect::<Vec<_>>()), 4);
+        let results = block_on(fetches.collect::<Vec<_>>());
+
+        assert!(results.iter().all(|(_, _, result)| result.is_ok()));
+        assert_eq!(backend.peak_connections(), 2);
+        assert_eq!(backend.open_connections(), 0);
+    }
+
+    #[test]
+    fn connections_are_released_after_errors_and_cancellation() {
+        let backend = Arc::new(MockBackend::default());
+        let dir = tempfile::tempdir().unwrap();
+
+        // `a` is not found, `b` refuses connections, and `c` never responds.
+        backend.serve(&uri("b"), MockFile::new(contents(4096)));
+        backend.fault(&uri("b"), None, Fault::ConnectionRefused);
+        backend.serve(&uri("c"), MockFile::new(contents(4096)));
+        backend.fault(&uri("c"), None, Fault::Stall(Duration::from_secs(600)));
+        backend.serve(&uri("d"), MockFile::new(contents(4096)));
+
+        let policy = RetryPolicy::default().on(ErrorClass::Connection, RetryDecision::Abort);
+        let fetcher = support::fetcher(&backend)
+            .retry_policy(policy)
+            .max_connections_per_host(1)
+            .build();
+
+        let stalled = source(dir.path(), "c");
+        let handle = stalled.handle.clone();
+
+        let inputs = vec![
+            (source(dir.path(), "a"), Arc::new(())),
+            (source(dir.path(), "b"), Arc::new(())),
+            (stalled, Arc::new(())),
+            (source(dir.path(), "d"), Arc::new(())),
+        ];
+
+        let fetches = fetcher.stream_from(futures::stream::iter(inputs), 4);
+
+        // Canceled once it holds the connection, while it waits for a response.
+        let cancel = async {
+            while !backend.requests().iter().any(|r| *r.uri == uri("c")) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+
+            handle.cancel();
+        };
+
+        // The fetches never complete if a connection was not released.
+        let fetches = future::join(fetches.collect(), cancel);
+        let results =
+            block_on(async { tokio::time::timeout(Duration::from_secs(10), fetches).await });
+
+        let (results, ()): (Vec<_>, ()) = results.expect("connections were not released");
+        let failed = |name: &str| {
+            let dest = dir.path().join(name);
+            let (_, _, result) = results.iter().find(|(path, ..)| **path == *dest).unwrap();
+            result.is_err()
+        };
+
+        assert!(failed("a") && failed("b") && failed("c"));
+        assert!(!failed("d"));
+        assert_eq!(backend.peak_connections(), 1);
+        assert_eq!(backend.open_connections(), 0);
+    }
+}
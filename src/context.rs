@@ -0,0 +1,203 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::{Error, ErrorClass};
+use http::StatusCode;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where an error occurred while fetching a file.
+#[derive(Clone, Debug)]
+pub struct ErrorContext {
+    /// The URL which was being fetched.
+    pub url: Box<str>,
+    /// Where the file was being fetched to.
+    pub dest: Arc<Path>,
+    /// How much of the file had been written when the error occurred, if it was
+    /// being received.
+    pub offset: Option<u64>,
+    /// The attempt which failed, counted from 1 since the fetch last made progress.
+    pub attempt: u16,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fetching {} into {}", self.url, self.dest.display())?;
+
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+
+        write!(f, ", attempt {}", self.attempt)
+    }
+}
+
+impl Error {
+    /// Whether the request which failed may succeed if it is attempted again.
+    ///
+    /// Lost connections, timeouts, and servers which are failing or overloaded are
+    /// retryable. A file which was not found, or is forbidden, may still be fetched
+    /// from another mirror, but not by retrying the same one.
+    ///
+    /// The fetcher only retries errors which are retryable, unless its `RetryPolicy`
+    /// sets a decision for the class of the error.
+    pub fn is_retryable(&self) -> bool {
+        match self.without_context() {
+            Error::AllMirrorsFailed(errors) => errors.iter().any(Error::is_retryable),
+            // Errors of a backend which are not known to be connection failures are
+            // retried, but not without limit.
+            Error::Backend(_) | Error::Paused | Error::RetryAfter(..) => true,
+            Error::Status(status) => {
+                status.is_server_error()
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            error => matches!(
+                ErrorClass::of(error),
+                ErrorClass::Connection | ErrorClass::Timeout | ErrorClass::NetworkChanged
+            ),
+        }
+    }
+
+    /// Whether the fetch can not succeed if it is attempted again, from any mirror.
+    ///
+    /// The fetcher gives up on a fetch as soon as it fails with a permanent error,
+    /// regardless of its retry policy.
+    pub fn is_permanent(&self) -> bool {
+        match self.without_context() {
+            Error::AllMirrorsFailed(errors) => errors.iter().all(Error::is_permanent),
+            Error::Canceled
+            | Error::InsufficientSpace(..)
+            | Error::Nameless
+            | Error::Parentless
+            | Error::TooLarge(_)
+            | Error::UnsupportedCompression(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Where the error occurred, if it occurred while fetching a file.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Context(context, _) => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The error without the context of where it occurred.
+    pub fn without_context(&self) -> &Error {
+        match self {
+            Error::Context(_, error) => error.without_context(),
+            error => error,
+        }
+    }
+
+    /// Adds the context of where the error occurred, if it does not have one already.
+    ///
+    /// Cancellations and pauses are not failures, and are left as they are.
+    pub(crate) fn with_context(self, context: impl FnOnce() -> ErrorContext) -> Self {
+        match self {
+            Error::AllMirrorsFailed(_) | Error::Canceled | Error::Context(..) | Error::Paused => {
+                self
+            }
+            error => Error::Context(Box::new(context()), Box::new(error)),
+        }
+    }
+}
+
+/// Lists the failure of each mirror.
+pub(crate) fn join(errors: &[Error]) -> String {
+    let mut list = String::new();
+
+    for error in errors {
+        if !list.is_empty() {
+            list.push_str("; ");
+        }
+
+        list.push_str(&describe(error));
+    }
+
+    list
+}
+
+/// Describes an error followed by each error which caused it, as the message of an
+/// error does not include its source.
+pub(crate) fn describe(error: &Error) -> String {
+    let mut description = error.to_string();
+    let mut source = std::error::Error::source(error);
+
+    while let Some(error) = source {
+        description.push_str(": ");
+        description.push_str(&error.to_string());
+        source = error.source();
+    }
+
+    description
+}
+
+/// The error of a fetch which gave up after `failed` mirror's last failure, listing the
+/// last failure of each mirror if every one of several mirrors failed.
+pub(crate) fn give_up(mut failures: Vec<Option<Error>>, failed: usize) -> Error {
+    if failures.len() > 1 && failures.iter().all(Option::is_some) {
+        return Error::AllMirrorsFailed(failures.into_iter().flatten().collect());
+    }
+
+    failures[failed]
+        .take()
+        .expect("failure of the mirror was recorded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::Path;
+
+    fn refused() -> Error {
+        let error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        Error::Backend(Box::new(error))
+    }
+
+    fn in_context(error: Error) -> Error {
+        error.with_context(|| ErrorContext {
+            url: "http://mirror/file".into(),
+            dest: Arc::from(Path::new("file")),
+            offset: Some(1000),
+            attempt: 2,
+        })
+    }
+
+    #[test]
+    fn context_is_displayed_without_the_error() {
+        let error = in_context(refused());
+        let context = "fetching http://mirror/file into file at byte 1000, attempt 2";
+
+        assert_eq!(error.to_string(), context);
+        assert_eq!(
+            std::error::Error::source(&error).map(ToString::to_string),
+            Some(refused().to_string())
+        );
+    }
+
+    #[test]
+    fn descriptions_include_each_source_once() {
+        let error = in_context(refused());
+
+        assert_eq!(
+            describe(&error),
+            "fetching http://mirror/file into file at byte 1000, attempt 2: \
+             http backend error: refused"
+        );
+
+        let error = Error::AllMirrorsFailed(vec![in_context(refused()), Error::TimedOut]);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "every mirror failed: {}; {}",
+                describe(&in_context(refused())),
+                Error::TimedOut
+            )
+        );
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::time::date_as_timestamp;
+use httpdate::HttpDate;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The layout of a multi-connection fetch, persisted next to its destination.
+///
+/// Records the length and modification time of the file being fetched, and the
+/// byte range that each part covers. When parts are fetched into part files, the
+/// progress of each part is the length of its part file, so the control file only
+/// needs to be written when the layout of the parts changes. When parts are written
+/// directly into the destination, the recorded position of each part is used instead.
+pub(crate) struct ControlFile {
+    path: PathBuf,
+    positional: bool,
+    lock: Mutex<()>,
+}
+
+/// A range of the destination which is fetched by a single connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PartRecord {
+    pub id: usize,
+    pub start: u64,
+    pub end: u64,
+    /// The offset after the last byte which was written when the record was saved.
+    pub position: u64,
+}
+
+impl ControlFile {
+    pub fn new(dest: &Path) -> Self {
+        let mut filename = dest.file_name().unwrap_or_default().to_os_string();
+        filename.push(".fetch-state");
+
+        Self {
+            path: dest.with_file_name(filename),
+            positional: false,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Whether the parts are written directly into the destination.
+    pub fn positional(mut self, positional: bool) -> Self {
+        self.positional = positional;
+        self
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Reads the recorded parts, if they were recorded for the same file.
+    ///
+    /// A file without a modification time can not be told apart from another file of
+    /// the same length, so its parts are never loaded.
+    pub fn load(&self, length: u64, modified: Option<HttpDate>) -> Option<Vec<PartRecord>> {
+        let modified = modified?;
+        let contents = fs::read_to_string(&self.path).ok()?;
+        let mut lines = contents.lines();
+
+        if lines.next()? != header(length, Some(modified), self.positional) {
+            return None;
+        }
+
+        lines
+            .map(|line| {
+                let mut fields = line.split(' ');
+
+                if fields.next()? != "part" {
+                    return None;
+                }
+
+                Some(PartRecord {
+                    id: fields.next()?.parse().ok()?,
+                    start: fields.next()?.parse().ok()?,
+                    end: fields.next()?.parse().ok()?,
+                    position: fields.next()?.parse().ok()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Atomically replaces the recorded parts.
+    pub fn save(
+        &self,
+        length: u64,
+        modified: Option<HttpDate>,
+        parts: &[PartRecord],
+    ) -> io::Result<()> {
+        let mut contents = header(length, modified, self.positional);
+
+        for part in parts {
+            contents.push_str(&format!(
+                "\npart {} {} {} {}",
+                part.id, part.start, part.end, part.position
+            ));
+        }
+
+        let _lock = self.lock.lock().unwrap();
+
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_data()?;
+
+        fs::rename(&temporary, &self.path)
+    }
+
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn header(length: u64, modified: Option<HttpDate>, positional: bool) -> String {
+    let mut header = format!("length {}", length);
+
+    if let Some(modified) = modified {
+        header.push_str(&format!(" modified {}", date_as_timestamp(modified)));
+    }
+
+    if positional {
+        header.push_str(" positional");
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn date(seconds: u64) -> HttpDate {
+        HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    fn parts() -> Vec<PartRecord> {
+        vec![
+            PartRecord {
+                id: 0,
+                start: 0,
+                end: 100,
+                position: 40,
+            },
+            PartRecord {
+                id: 2,
+                start: 100,
+                end: 200,
+                position: 100,
+            },
+        ]
+    }
+
+    #[test]
+    fn saved_parts_are_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = ControlFile::new(&dir.path().join("file"));
+
+        assert!(!control.exists());
+        assert_eq!(control.load(200, None), None);
+
+        control.save(200, Some(date(1000)), &parts()).unwrap();
+
+        assert!(control.exists());
+        assert_eq!(control.load(200, Some(date(1000))), Some(parts()));
+
+        control.remove();
+        assert!(!control.exists());
+    }
+
+    #[test]
+    fn parts_of_a_different_file_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        let control = ControlFile::new(&dest);
+
+        control.save(200, Some(date(1000)), &parts()).unwrap();
+
+        assert_eq!(control.load(201, Some(date(1000))), None);
+        assert_eq!(control.load(200, Some(date(1001))), None);
+        assert_eq!(control.load(200, None), None);
+
+        // Parts fetched into part files are not those written into the destination.
+        let positional = ControlFile::new(&dest).positional(true);
+        assert_eq!(positional.load(200, Some(date(1000))), None);
+    }
+
+    #[test]
+    fn malformed_parts_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = ControlFile::new(&dir.path().join("file"));
+
+        for contents in [
+            "length 200 modified 1000\npart 0 0 100",
+            "length 200 modified 1000\npart 0 0 100 x",
+            "length 200 modified 1000\nrange 0 0 100 40",
+            "length 200 modified 1000\npart 0 0 100 40\npart 1",
+            "",
+        ]
+        .iter()
+        {
+            fs::write(&control.path, contents).unwrap();
+            assert_eq!(control.load(200, Some(date(1000))), None, "{:?}", contents);
+        }
+
+        fs::write(&control.path, "length 200 modified 1000\npart 0 0 200 40").unwrap();
+        assert!(control.load(200, Some(date(1000))).is_some());
+    }
+
+    #[test]
+    fn parts_of_a_file_without_a_modification_time_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = ControlFile::new(&dir.path().join("file"));
+
+        // Another file of the same length may have been fetched into the parts.
+        control.save(200, None, &parts()).unwrap();
+        assert_eq!(control.load(200, None), None);
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::checksum::ChecksumHasher;
+use crate::Error;
+use http::HeaderMap;
+use std::io::{self, Write};
+
+/// A compression format which a source may be decompressed from as it is fetched.
+///
+/// Each format is decoded by a codec behind the feature of the same name: `gzip`, `xz`,
+/// `bzip2` and `zstd`. Fetching a source in a format whose feature is not enabled fails
+/// with `Error::UnsupportedCompression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    /// The format of a file, from the extension at the end of its path or URL.
+    pub fn from_extension(path: &str) -> Option<Self> {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        let (_, extension) = path.rsplit_once('.')?;
+
+        match extension {
+            "gz" | "tgz" => Some(Compression::Gzip),
+            "xz" | "txz" => Some(Compression::Xz),
+            "bz2" | "tbz2" => Some(Compression::Bzip2),
+            "zst" | "tzst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The format of a file, from its `Content-Type`.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+        match mime {
+            "application/gzip" | "application/x-gzip" => Some(Compression::Gzip),
+            "application/x-xz" => Some(Compression::Xz),
+            "application/x-bzip2" => Some(Compression::Bzip2),
+            "application/zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Detects the format from the URL of a file, and then from its response headers.
+    pub(crate) fn detect(uri: &str, headers: &HeaderMap) -> Option<Self> {
+        Self::from_extension(uri).or_else(|| {
+            let content_type = headers.get("content-type")?.to_str().ok()?;
+            Self::from_content_type(content_type)
+        })
+    }
+}
+
+/// Decompresses a source while it is being fetched.
+///
+/// A decompressed file can not be resumed from the middle of the stream, so a source
+/// which is decompressed is fetched with a single connection, and fetched again from the
+/// start when a connection fails.
+///
+/// ```
+/// use async_fetcher::{Compression, Decompress};
+///
+/// // Decompress an xz file, validating the checksum of the decompressed file.
+/// let decompress = Decompress::new()
+///     .format(Compression::Xz)
+///     .checksum_decompressed(true);
+/// ```
+#[derive(Clone, Copy, Debug, Default, new, Setters)]
+pub struct Decompress {
+    /// The format to decompress the source from.
+    /// # Note
+    /// Defaults to detecting the format from the extension of the URL, and then from
+    /// the `Content-Type` of the response. The source is stored as it is served if no
+    /// format is detected.
+    #[new(default)]
+    #[setters(strip_option)]
+    pub(crate) format: Option<Compression>,
+
+    /// Validates the checksum of the source against the decompressed bytes.
+    /// # Note
+    /// Defaults to validating the compressed bytes, as they are served.
+    #[new(default)]
+    pub(crate) checksum_decompressed: bool,
+}
+
+/// Decodes compressed bytes into a writer.
+pub(crate) trait Decoder: Write {
+    /// Writes the remainder of the decoded stream, and verifies that it was complete.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+#[cfg(feature = "gzip")]
+impl<W: Write> Decoder for flate2::write::MultiGzDecoder<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.try_finish()
+    }
+}
+
+#[cfg(feature = "xz")]
+impl<W: Write> Decoder for xz2::write::XzDecoder<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        xz2::write::XzDecoder::finish(&mut self).map(|_| ())
+    }
+}
+
+/// A codec which decodes one compressed stream, or frame, at a time.
+///
+/// The writers of the bzip2 and zstd crates can not tell whether the last stream was
+/// complete, so a stream which was cut off would otherwise be accepted, or never finish.
+#[cfg(any(feature = "bzip2", feature = "zstd"))]
+trait Codec {
+    /// Decodes bytes of `input` into the spare capacity of `output`, returning how many
+    /// bytes of the input were read, and whether the end of the stream was reached.
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<(usize, bool)>;
+
+    /// Prepares to decode another stream, which follows the end of the last one.
+    fn restart(&mut self) -> io::Result<()>;
+}
+
+#[cfg(feature = "bzip2")]
+impl Codec for bzip2::Decompress {
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<(usize, bool)> {
+        let before = self.total_in();
+        let status = self
+            .decompress_vec(input, output)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        let read = (self.total_in() - before) as usize;
+        Ok((read, status == bzip2::Status::StreamEnd))
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        *self = bzip2::Decompress::new(false);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for zstd::stream::raw::Decoder<'static> {
+    fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<(usize, bool)> {
+        use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+        let mut input = InBuffer::around(input);
+        let hint = self
+            .run(&mut input, &mut OutBuffer::around(output))
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        Ok((input.pos(), hint == 0))
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        zstd::stream::raw::Operation::reinit(self)
+    }
+}
+
+/// Decodes compressed bytes with a codec, tracking whether they end with a complete
+/// stream.
+#[cfg(any(feature = "bzip2", feature = "zstd"))]
+struct StreamDecoder<C, W> {
+    codec: C,
+    output: W,
+    buffer: Vec<u8>,
+    /// Whether the bytes decoded so far end at the end of a stream.
+    ended: bool,
+}
+
+#[cfg(any(feature = "bzip2", feature = "zstd"))]
+impl<C: Codec, W: Write> StreamDecoder<C, W> {
+    fn new(codec: C, output: W) -> Self {
+        Self {
+            codec,
+            output,
+            buffer: Vec::with_capacity(128 * 1024),
+            ended: false,
+        }
+    }
+}
+
+#[cfg(any(feature = "bzip2", feature = "zstd"))]
+impl<C: Codec, W: Write> Write for StreamDecoder<C, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut read = 0;
+
+        loop {
+            if self.ended {
+                if read == buf.len() {
+                    return Ok(buf.len());
+                }
+
+                self.codec.restart()?;
+            }
+
+            self.buffer.clear();
+            let (consumed, ended) = self.codec.decode(&buf[read..], &mut self.buffer)?;
+
+            read += consumed;
+            self.ended = ended;
+            self.output.write_all(&self.buffer)?;
+
+            // Decoded bytes may be left in the codec while its output is full.
+            if read == buf.len() && self.buffer.len() < self.buffer.capacity() {
+                return Ok(buf.len());
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(any(feature = "bzip2", feature = "zstd"))]
+impl<C: Codec, W: Write> Decoder for StreamDecoder<C, W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        if !self.ended {
+            let why = "compressed stream ended before its end";
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, why));
+        }
+
+        self.flush()
+    }
+}
+
+/// Creates a decoder of the format which writes into `output`.
+#[cfg_attr(
+    not(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd")),
+    allow(unused_variables)
+)]
+pub(crate) fn decoder<'a, W: Write + Send + 'a>(
+    format: Compression,
+    output: W,
+) -> Result<Box<dyn Decoder + Send + 'a>, Error> {
+    match format {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(flate2::write::MultiGzDecoder::new(output))),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Ok(Box::new(xz2::write::XzDecoder::new(output))),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Ok(Box::new(StreamDecoder::new(
+            bzip2::Decompress::new(false),
+            output,
+        ))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::stream::raw::Decoder::new()
+            .map(|codec| Box::new(StreamDecoder::new(codec, output)) as Box<dyn Decoder + Send>)
+            .map_err(Error::Decompress),
+        #[allow(unreachable_patterns)]
+        format => Err(Error::UnsupportedCompression(format)),
+    }
+}
+
+/// Whether an error from a decoder was caused by the compressed bytes, rather than by
+/// writing the decoded bytes into the file.
+pub(crate) fn is_corrupt(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Writes bytes into a file, computing their checksum as they are written.
+pub(crate) struct HashingWriter<'a, W> {
+    pub inner: W,
+    pub hasher: Option<&'a mut ChecksumHasher>,
+}
+
+impl<'a, W: Write> Write for HashingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..written]);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Bytes which are not compressed are written as they are.
+impl<'a, W: Write> Decoder for HashingWriter<'a, W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd"))]
+    fn data() -> Vec<u8> {
+        (0..200_000u32).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd"))]
+    fn compress(format: Compression, data: &[u8]) -> Vec<u8> {
+        match format {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            #[cfg(feature = "xz")]
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(data, 0).unwrap(),
+            #[allow(unreachable_patterns)]
+            format => panic!("{:?} is not enabled", format),
+        }
+    }
+
+    /// Decodes the input in chunks, as they would be read from a response.
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd"))]
+    fn decode(format: Compression, input: &[u8], output: impl Write + Send) -> io::Result<()> {
+        let mut decoder = match decoder(format, output) {
+            Ok(decoder) => decoder,
+            Err(why) => panic!("{}", why),
+        };
+
+        for chunk in input.chunks(8192) {
+            decoder.write_all(chunk)?;
+        }
+
+        decoder.finish()
+    }
+
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd"))]
+    fn assert_decodes(format: Compression) {
+        let data = data();
+        let mut output = Vec::new();
+        decode(format, &compress(format, &data), &mut output).unwrap();
+        assert!(output == data);
+    }
+
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd"))]
+    fn assert_rejects_truncation(format: Compression) {
+        let compressed = compress(format, &data());
+        let truncated = &compressed[..compressed.len() / 2];
+
+        let why = decode(format, truncated, Vec::new()).unwrap_err();
+        assert!(is_corrupt(&why), "{:?}", why);
+    }
+
+    /// Fails every write, as a full disk would.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(any(feature = "gzip", feature = "xz", feature = "bzip2", feature = "zstd"))]
+    fn assert_write_error_is_not_corrupt(format: Compression) {
+        let why = decode(format, &compress(format, &data()), FullDisk).unwrap_err();
+        assert!(!is_corrupt(&why), "{:?}", why);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip() {
+        assert_decodes(Compression::Gzip);
+        assert_rejects_truncation(Compression::Gzip);
+        assert_write_error_is_not_corrupt(Compression::Gzip);
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz() {
+        assert_decodes(Compression::Xz);
+        assert_rejects_truncation(Compression::Xz);
+        assert_write_error_is_not_corrupt(Compression::Xz);
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn bzip2() {
+        assert_decodes(Compression::Bzip2);
+        assert_rejects_truncation(Compression::Bzip2);
+        assert_write_error_is_not_corrupt(Compression::Bzip2);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        assert_decodes(Compression::Zstd);
+        assert_rejects_truncation(Compression::Zstd);
+        assert_write_error_is_not_corrupt(Compression::Zstd);
+    }
+
+    /// Decodes a stream which decodes to far more than the output buffer of the decoder
+    /// from each chunk, followed by another stream.
+    #[cfg(any(feature = "bzip2", feature = "gzip", feature = "zstd"))]
+    fn assert_decodes_concatenated(format: Compression) {
+        let zeros = vec![0u8; 4 * 1024 * 1024];
+        let mut compressed = compress(format, &zeros);
+        compressed.extend(compress(format, &data()));
+
+        let mut output = Vec::new();
+        decode(format, &compressed, &mut output).unwrap();
+        assert_eq!(output.len(), zeros.len() + data().len());
+        assert!(output[zeros.len()..] == data());
+
+        let why = decode(format, &[], Vec::new()).unwrap_err();
+        assert!(is_corrupt(&why));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_concatenated() {
+        assert_decodes_concatenated(Compression::Gzip);
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn bzip2_concatenated() {
+        assert_decodes_concatenated(Compression::Bzip2);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_concatenated() {
+        assert_decodes_concatenated(Compression::Zstd);
+    }
+
+    #[test]
+    fn uncompressed_write_errors() {
+        let mut writer = Box::new(HashingWriter {
+            inner: FullDisk,
+            hasher: None,
+        });
+
+        let why = writer.write_all(b"data").unwrap_err();
+        assert!(!is_corrupt(&why));
+    }
+}
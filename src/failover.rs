@@ -0,0 +1,360 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+
+/// The mirrors of a fetch, and which of them is being fetched from.
+pub(crate) struct Failover {
+    uris: Arc<[Box<str>]>,
+    /// The mirror currently being fetched from, and how many have failed in a row.
+    mirror: usize,
+    failed_mirrors: usize,
+    announced: Option<usize>,
+    /// Mirrors which served a file that did not match its checksum.
+    blacklisted: Vec<bool>,
+    /// The last failure of each mirror, which are listed if every mirror fails.
+    failures: Vec<Option<Error>>,
+    /// After a mismatch, only one mirror is fetched from at a time, so that a
+    /// mismatch can be attributed to the mirror which served the file.
+    exclusive: bool,
+}
+
+impl Failover {
+    pub fn new(uris: Arc<[Box<str>]>) -> Self {
+        Self {
+            mirror: 0,
+            failed_mirrors: 0,
+            announced: None,
+            blacklisted: vec![false; uris.len()],
+            failures: (0..uris.len()).map(|_| None).collect(),
+            exclusive: false,
+            uris,
+        }
+    }
+
+    /// The mirror currently being fetched from.
+    pub fn uri(&self) -> &str {
+        &self.uris[self.mirror]
+    }
+
+    /// The mirrors which may be fetched from, with the selected mirror placed first.
+    pub fn mirrors(&self) -> Arc<[Box<str>]> {
+        (0..self.uris.len())
+            .map(|offset| (self.mirror + offset) % self.uris.len())
+            .filter(|&index| !self.blacklisted[index])
+            .take(if self.exclusive { 1 } else { self.uris.len() })
+            .map(|index| self.uris[index].clone())
+            .collect()
+    }
+
+    /// The event announcing the selected mirror, if it has changed since the last.
+    pub fn announce(&mut self) -> Option<FetchEvent> {
+        if self.announced == Some(self.mirror) {
+            return None;
+        }
+
+        let uri = self.uris[self.mirror].clone();
+        let event = match self.announced {
+            Some(previous) => FetchEvent::MirrorSwitched(self.uris[previous].clone(), uri),
+            None => FetchEvent::Mirror(uri),
+        };
+
+        self.announced = Some(self.mirror);
+        Some(event)
+    }
+
+    /// The error to fail with after giving up on the `failed` mirror.
+    fn give_up(&mut self, failed: usize) -> Error {
+        context::give_up(std::mem::take(&mut self.failures), failed)
+    }
+
+    /// Selects the next mirror which has not been blacklisted, wrapping around.
+    fn next_mirror(&mut self) {
+        self.mirror = (1..=self.uris.len())
+            .map(|offset| (self.mirror + offset) % self.uris.len())
+            .find(|&index| !self.blacklisted[index])
+            .unwrap_or(self.mirror);
+    }
+}
+
+impl<Data: Send + Sync + 'static, C: HttpBackend> Fetcher<Data, C> {
+    /// Decides how a fetch continues after an attempt failed with `error`.
+    ///
+    /// The fetch fails over to the next mirror until each of them has failed once,
+    /// and then waits before retrying as defined by the retry policy. A mirror which
+    /// served a corrupted file is not used again. Returns the error to fail with if
+    /// the fetch should give up.
+    pub(crate) async fn recover(
+        &self,
+        failover: &mut Failover,
+        error: Error,
+        attempts: &Attempts,
+        dest: &Arc<Path>,
+        extra: &Arc<Data>,
+    ) -> Result<(), Error> {
+        if error.is_permanent() {
+            return Err(error);
+        }
+
+        let available = failover.mirrors().len();
+        let failed = failover.mirror;
+
+        // A corrupted file is fetched again from the start, from another mirror.
+        if let Error::Checksum(_) | Error::Decompress(_) = error.without_context() {
+            failover.failures[failed] = Some(error);
+
+            if available == 1 || self.connections_per_file == 1 {
+                error!("{} served a corrupted file", failover.uris[failed]);
+                failover.blacklisted[failed] = true;
+                self.mirror_health.record_failure(&failover.uris[failed]);
+
+                if failover.blacklisted.iter().all(|&bad| bad) {
+                    return Err(failover.give_up(failed));
+                }
+
+                failover.next_mirror();
+            }
+
+            failover.exclusive = true;
+            failover.failed_mirrors = 0;
+            return Ok(());
+        }
+
+        // Immediately try the next mirror until each of them has failed once.
+        let available = failover.blacklisted.iter().filter(|&&bad| !bad).count();
+        if available > 1 && is_mirror_failure(&error) {
+            failover.failed_mirrors += 1;
+            failover.next_mirror();
+
+            if failover.failed_mirrors < available {
+                let reason = context::describe(&error);
+                error!("failing over to {} after error: {}", failover.uri(), reason);
+                failover.failures[failed] = Some(error);
+                return Ok(());
+            }
+
+            failover.failed_mirrors = 0;
+        }
+
+        let retry = self
+            .backoff(&error, failover.uri(), attempts, dest, extra)
+            .await;
+
+        failover.failures[failed] = Some(error);
+
+        if !retry {
+            return Err(failover.give_up(failed));
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the error was caused by the mirror, rather than the local system.
+fn is_mirror_failure(error: &Error) -> bool {
+    if let Error::RetryAfter(..) = error.without_context() {
+        return true;
+    }
+
+    matches!(
+        ErrorClass::of(error),
+        ErrorClass::Connection
+            | ErrorClass::Timeout
+            | ErrorClass::ServerError
+            | ErrorClass::ClientError
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, block_on, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+
+    fn corrupted(length: usize) -> Vec<u8> {
+        let mut corrupted = contents(length);
+        corrupted[length / 2] ^= 1;
+        corrupted
+    }
+
+    fn gets(backend: &MockBackend, uri: &str) -> usize {
+        let requests = backend.requests();
+        let gets = requests.iter().filter(|r| r.method == http::Method::GET);
+        gets.filter(|r| &*r.uri == uri).count()
+    }
+
+    #[test]
+    fn failing_mirrors_are_skipped_without_waiting() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://b/file", MockFile::new(contents(4096)));
+        backend.serve("http://c/file", MockFile::new(contents(4096)));
+        backend.fault("http://b/file", None, Fault::ConnectionRefused);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        // A retry of the same mirror would take far longer than the test.
+        let policy = RetryPolicy::default().base_delay(Duration::from_secs(600));
+        let fetcher = support::fetcher(&backend).retry_policy(policy);
+
+        let uris = ["http://a/file", "http://b/file", "http://c/file"];
+        let report = support::fetch(fetcher, &uris, &dest).unwrap();
+
+        assert_eq!(report.url.as_deref(), Some("http://c/file"));
+        assert_eq!(report.retries, 2);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(4096));
+    }
+
+    #[test]
+    fn every_mirror_failing_lists_each_failure() {
+        let backend = Arc::new(MockBackend::default());
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let uris = ["http://a/file", "http://b/file"];
+        let error = support::fetch(support::fetcher(&backend), &uris, &dest).unwrap_err();
+
+        match error {
+            Error::AllMirrorsFailed(errors) => {
+                assert_eq!(errors.len(), 2);
+                assert!(errors
+                    .iter()
+                    .all(|error| matches!(error.without_context(), Error::Status(_))));
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+
+        // A file which is not found is not requested from the same mirror again.
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn sinks_fail_over() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://a/file", MockFile::new(contents(4096)));
+        backend.serve("http://b/file", MockFile::new(contents(4096)));
+        backend.fault("http://a/file", None, Fault::Disconnect { after: 1000 });
+
+        let uris: Arc<[Box<str>]> =
+            Arc::from(vec![Box::from("http://a/file"), Box::from("http://b/file")]);
+
+        let fetcher = support::fetcher(&backend).build();
+        let name: Arc<Path> = Arc::from(Path::new("file"));
+        let request = fetcher.request_bytes(uris, name, 1 << 20, Arc::new(()));
+        let (bytes, report) = block_on(request).unwrap();
+
+        assert_eq!(&bytes[..], &contents(4096)[..]);
+        assert_eq!(report.resumed, 1000);
+        assert_eq!(report.mirrors.len(), 2);
+
+        // The second mirror continues from the bytes which the first one sent.
+        let range = backend.requests()[1].headers.get("range").cloned();
+        assert_eq!(range.unwrap(), "bytes=1000-");
+    }
+
+    #[test]
+    fn mirrors_which_serve_corrupted_files_are_not_used_again() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://a/file", MockFile::new(corrupted(64 * 1024)));
+        backend.serve("http://b/file", MockFile::new(contents(64 * 1024)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let uris = ["http://a/file", "http://b/file"];
+        let fetcher = support::fetcher(&backend);
+        let report = support::fetch_checked(fetcher, &uris, &dest, &contents(64 * 1024)).unwrap();
+
+        assert_eq!(report.url.as_deref(), Some("http://b/file"));
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(64 * 1024));
+        assert_eq!(gets(&backend, "http://a/file"), 1);
+        assert_eq!(gets(&backend, "http://b/file"), 1);
+    }
+
+    #[test]
+    fn corrupted_parts_are_fetched_again_from_one_mirror_at_a_time() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://a/file", MockFile::new(corrupted(256 * 1024)));
+        backend.serve("http://b/file", MockFile::new(contents(256 * 1024)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = support::fetcher(&backend)
+            .connections_per_file(2)
+            .max_part_size(32 * 1024);
+
+        let uris = ["http://a/file", "http://b/file"];
+        support::fetch_checked(fetcher, &uris, &dest, &contents(256 * 1024)).unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(256 * 1024));
+    }
+
+    #[test]
+    fn every_mirror_serving_corrupted_files_fails() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://a/file", MockFile::new(corrupted(4096)));
+        backend.serve("http://b/file", MockFile::new(corrupted(4096)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let uris = ["http://a/file", "http://b/file"];
+        let fetcher = support::fetcher(&backend);
+        let error = support::fetch_checked(fetcher, &uris, &dest, &contents(4096)).unwrap_err();
+
+        match error {
+            Error::AllMirrorsFailed(errors) => {
+                assert_eq!(errors.len(), 2);
+                assert!(errors
+                    .iter()
+                    .all(|error| matches!(error.without_context(), Error::Checksum(_))));
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+
+        assert!(!dest.exists());
+        assert_eq!(gets(&backend, "http://a/file"), 1);
+        assert_eq!(gets(&backend, "http://b/file"), 1);
+    }
+
+    #[test]
+    fn corrupted_partial_files_do_not_blacklist_the_mirror() {
+        let modified = HttpDate::from(std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let file = MockFile::new(contents(4096)).last_modified(modified);
+
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://a/file", file.clone());
+        backend.serve("http://b/file", file);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        // A partial file from an earlier fetch, which was corrupted on disk.
+        let mut partial = contents(2048);
+        partial[1024] ^= 1;
+        std::fs::write(dir.path().join("file.fetching"), partial).unwrap();
+
+        let uris = ["http://a/file", "http://b/file"];
+        let fetcher = support::fetcher(&backend);
+        let report = support::fetch_checked(fetcher, &uris, &dest, &contents(4096)).unwrap();
+
+        assert_eq!(report.url.as_deref(), Some("http://a/file"));
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(4096));
+
+        // The file is fetched again from the start, from the same mirror.
+        let requests = backend.requests();
+        let gets: Vec<_> = requests
+            .iter()
+            .filter(|r| r.method == http::Method::GET)
+            .collect();
+
+        assert_eq!(gets.len(), 2);
+        assert!(gets.iter().all(|r| &*r.uri == "http://a/file"));
+        let range = gets[0].headers.get("range").unwrap().to_str().unwrap();
+        assert!(range.starts_with("bytes=2048-"));
+        assert!(gets[1].headers.get("range").is_none());
+    }
+}
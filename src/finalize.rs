@@ -0,0 +1,159 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::Error;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// How much is synced to disk before a fetched file replaces its destination.
+///
+/// Files are fetched into a staging file next to the destination, which is renamed over
+/// the destination once it has been fetched and validated. The destination is never left
+/// with a partially fetched file, and a crash before the rename leaves the previous file
+/// in place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Renames the file without syncing it, which may leave the destination empty or
+    /// incomplete after a crash of the system.
+    None,
+    /// Syncs the contents of the file before it is renamed.
+    File,
+    /// Syncs the contents of the file before it is renamed, and the directory after it
+    /// has been renamed, so that the rename survives a crash of the system.
+    #[default]
+    Full,
+}
+
+/// The staging file which a destination is fetched into when it has no partial path.
+pub(crate) fn staging_path(dest: &Path) -> Arc<Path> {
+    let mut filename = dest.file_name().unwrap_or_default().to_os_string();
+    filename.push(".fetching");
+    Arc::from(dest.with_file_name(filename))
+}
+
+/// Replaces the destination with a staging file which has been fetched.
+pub(crate) async fn finalize(
+    staging: Arc<Path>,
+    dest: Arc<Path>,
+    durability: Durability,
+) -> Result<(), Error> {
+    let task = move || {
+        if durability != Durability::None {
+            File::open(&staging)
+                .and_then(|file| file.sync_all())
+                .map_err(Error::Sync)?;
+        }
+
+        std::fs::rename(&staging, &dest).map_err(Error::Rename)?;
+
+        if durability == Durability::Full {
+            sync_parent(&dest).map_err(Error::Sync)?;
+        }
+
+        Ok(())
+    };
+
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(Error::TokioSpawn)?
+}
+
+/// Syncs the directory entries of the directory containing `path`.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => File::open(".")?.sync_all(),
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Directories can not be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, block_on, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use crate::{ErrorClass, RetryDecision, RetryPolicy};
+
+    #[test]
+    fn staging_files_are_named_after_the_destination() {
+        let staging = staging_path(Path::new("/dir/file.tar"));
+        assert_eq!(&*staging, Path::new("/dir/file.tar.fetching"));
+    }
+
+    #[test]
+    fn staging_files_replace_the_destination() {
+        for &durability in &[Durability::None, Durability::File, Durability::Full] {
+            let dir = tempfile::tempdir().unwrap();
+            let dest: Arc<Path> = Arc::from(dir.path().join("file"));
+            let staging = staging_path(&dest);
+
+            std::fs::write(&dest, b"old").unwrap();
+            std::fs::write(&staging, b"new").unwrap();
+
+            block_on(finalize(staging.clone(), dest.clone(), durability)).unwrap();
+
+            assert_eq!(std::fs::read(&dest).unwrap(), b"new", "{:?}", durability);
+            assert!(!staging.exists());
+        }
+    }
+
+    #[test]
+    fn destinations_are_untouched_when_finalizing_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest: Arc<Path> = Arc::from(dir.path().join("file"));
+        std::fs::write(&dest, b"old").unwrap();
+
+        let result = block_on(finalize(
+            staging_path(&dest),
+            dest.clone(),
+            Durability::None,
+        ));
+
+        assert!(matches!(result, Err(Error::Rename(_))));
+        assert_eq!(std::fs::read(&dest).unwrap(), b"old");
+    }
+
+    #[test]
+    fn fetches_replace_the_destination_once_complete() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://mirror/file", MockFile::new(contents(64 * 1024)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        std::fs::write(&dest, b"old").unwrap();
+
+        support::fetch(support::fetcher(&backend), &["http://mirror/file"], &dest).unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(64 * 1024));
+        assert!(!staging_path(&dest).exists());
+    }
+
+    #[test]
+    fn failed_fetches_leave_the_destination_untouched() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://mirror/file", MockFile::new(contents(64 * 1024)));
+        let disconnect = Fault::Disconnect { after: 10_000 };
+        backend.fault("http://mirror/file", Some(http::Method::GET), disconnect);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        std::fs::write(&dest, b"old").unwrap();
+
+        let policy = RetryPolicy::default().on(ErrorClass::Connection, RetryDecision::Abort);
+        let fetcher = support::fetcher(&backend).retry_policy(policy);
+        assert!(support::fetch(fetcher, &["http://mirror/file"], &dest).is_err());
+
+        // The data fetched so far is kept in the staging file for a later attempt.
+        assert_eq!(std::fs::read(&dest).unwrap(), b"old");
+        assert_eq!(std::fs::read(staging_path(&dest)).unwrap().len(), 10_000);
+    }
+}
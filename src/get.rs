@@ -2,10 +2,16 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use super::*;
+use crate::checksum::ChecksumHasher;
+use crate::decompress::{self, Decoder, HashingWriter};
+use crate::report::ReportRecorder;
+use crate::retry::Attempts;
+use crate::scheduler::RangePart;
+use http::request::Builder as HttpBuilder;
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -30,118 +36,177 @@ impl FetchLocation {
 
         Ok(Self { file, dest })
     }
+
+    /// Opens an existing file to be written from `position`, without truncating it.
+    pub async fn open_at(dest: Arc<Path>, position: u64) -> Result<Self, crate::Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(&dest)
+            .map_err(Error::FileCreate)?;
+
+        file.seek(SeekFrom::Start(position)).map_err(Error::Write)?;
+
+        Ok(Self { file, dest })
+    }
+}
+
+/// The request for a file, or the response which was received for one.
+pub(crate) enum GetRequest {
+    Send(HttpRequest<()>),
+    Received(Box<str>, HttpResponse<Body>),
+}
+
+impl From<HttpBuilder> for GetRequest {
+    fn from(request: HttpBuilder) -> Self {
+        GetRequest::Send(request.body(()).expect("failed to build request"))
+    }
 }
-pub(crate) async fn get<Data: Send + Sync + 'static>(
-    fetcher: Arc<Fetcher<Data>>,
-    request: RequestBuilder,
+
+/// Fetches a response into a file.
+///
+/// If the options have a checksum, the response must complete the file, and the file
+/// is validated against it once every byte has been written.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn get<Data: Send + Sync + 'static, C: HttpBackend>(
+    fetcher: Arc<Fetcher<Data, C>>,
+    request: impl Into<GetRequest>,
     file: FetchLocation,
     final_destination: Arc<Path>,
     extra: Arc<Data>,
-    attempts: Arc<AtomicU16>,
+    attempts: Arc<Attempts>,
+    part: Option<Arc<RangePart>>,
+    options: FetchOptions,
 ) -> Result<(Arc<Path>, File), crate::Error> {
     crate::utils::shutdown_check(&fetcher.shutdown)?;
 
     let shutdown = fetcher.shutdown.clone();
     let FetchLocation { file, dest } = file;
 
+    // Stops the blocking task from writing to the file if this future is dropped.
+    let abandoned = AbandonGuard(Arc::new(AtomicBool::new(false)));
+    let abandoned_flag = abandoned.0.clone();
+
+    let request = request.into();
+
+    let uri = match &request {
+        GetRequest::Send(request) => request.uri().to_string(),
+        GetRequest::Received(uri, _) => uri.to_string(),
+    };
+
+    // Describes where an error occurred, from how far the part or file got.
+    let context = {
+        let uri = uri.clone();
+        let dest = dest.clone();
+        let final_destination = final_destination.clone();
+        let part = part.clone();
+        let attempts = attempts.clone();
+
+        move || ErrorContext {
+            url: uri.into(),
+            offset: match part {
+                Some(part) => Some(part.position()),
+                None => std::fs::metadata(&dest).map(|metadata| metadata.len()).ok(),
+            },
+            dest: final_destination,
+            attempt: attempts.failed().saturating_add(1),
+        }
+    };
+
     let main = async move {
         let _token = match shutdown.delay_shutdown_token() {
             Ok(token) => token,
             Err(_) => return Err(Error::Canceled),
         };
 
-        match &fetcher.client {
-            #[cfg(feature = "isahc")]
-            Client::Isahc(client) => {
-                // If no extra features are enabled this if-let is useless
-                #[allow(irrefutable_let_patterns)]
-                if let RequestBuilder::Http(request) = request {
-                    let request = request.body(()).expect("failed to build request");
-
-                    let req = async { client.send_async(request).await.map_err(Error::from) };
-
-                    let initial_response =
-                        crate::utils::timed_interrupt(Duration::from_secs(10), req).await?;
-
-                    if initial_response.status() == StatusCode::NOT_MODIFIED {
-                        return Ok::<_, crate::Error>((dest, file));
-                    }
-
-                    let response = validate_isahc(initial_response)?.into_body();
-
-                    fetch_loop(
-                        fetcher,
-                        file,
-                        dest,
-                        final_destination,
-                        extra,
-                        attempts,
-                        shutdown,
-                        response,
-                    )
-                    .await
-                } else {
-                    Err(crate::Error::InvalidGetRequestBuilder)
-                }
-            }
-            #[cfg(feature = "reqwest")]
-            Client::Reqwest(client) =>
-            {
-                #[allow(irrefutable_let_patterns)]
-                if let RequestBuilder::Reqwest(request) = request {
-                    let request = request.build().expect("failed to build request");
-
-                    let req = async { client.execute(request).await.map_err(Error::from) };
-
-                    let initial_response =
-                        crate::utils::timed_interrupt(Duration::from_secs(10), req).await?;
-
-                    if initial_response.status() == StatusCode::NOT_MODIFIED {
-                        return Ok::<_, crate::Error>((dest, file));
-                    }
-
-                    let response = validate_reqwest(initial_response)?
-                        .bytes_stream()
-                        .map_err(|e| futures::io::Error::new(futures::io::ErrorKind::Other, e))
-                        .into_async_read();
-
-                    fetch_loop(
-                        fetcher,
-                        file,
-                        dest,
-                        final_destination,
-                        extra,
-                        attempts,
-                        shutdown,
-                        response,
-                    )
-                    .await
-                } else {
-                    Err(crate::Error::InvalidGetRequestBuilder)
-                }
+        let initial_response = match request {
+            GetRequest::Send(request) => {
+                fetcher
+                    .dispatch(request, Some(Duration::from_secs(10)))
+                    .await?
             }
+            GetRequest::Received(_, response) => response,
+        };
+
+        if initial_response.status() == StatusCode::NOT_MODIFIED {
+            return Ok::<_, crate::Error>((dest, file));
         }
+
+        let response = validate(initial_response)?;
+
+        let effective = response.extensions().get();
+        options.report.response(&uri, response.headers(), effective);
+
+        // The format is detected from the response if it was not given.
+        let decompress = options.decompress.map(|decompress| Decompress {
+            format: decompress
+                .format
+                .or_else(|| Compression::detect(&uri, response.headers())),
+            ..decompress
+        });
+
+        let response = response.into_body();
+
+        fetch_loop(
+            fetcher.clone(),
+            &uri,
+            file,
+            dest,
+            final_destination,
+            extra,
+            attempts,
+            shutdown,
+            response,
+            part,
+            options.checksum.as_ref().map(Checksum::hasher),
+            decompress,
+            options.rate_limit,
+            options.handle,
+            &options.report,
+            &abandoned_flag,
+        )
+        .await
     };
 
     tokio::task::spawn_blocking(|| futures::executor::block_on(main))
         .await
         .unwrap()
+        .map_err(|why| why.with_context(context))
+}
+
+/// Flags a fetch as abandoned when the future awaiting it is dropped.
+struct AbandonGuard(Arc<AtomicBool>);
+
+impl Drop for AbandonGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn fetch_loop<Data: Send + Sync + 'static, Response: AsyncRead + Unpin>(
-    fetcher: Arc<Fetcher<Data>>,
+async fn fetch_loop<Data: Send + Sync + 'static, C: HttpBackend>(
+    fetcher: Arc<Fetcher<Data, C>>,
+    uri: &str,
     mut file: File,
     dest: Arc<Path>,
     final_destination: Arc<Path>,
     extra: Arc<Data>,
-    attempts: Arc<AtomicU16>,
+    attempts: Arc<Attempts>,
     shutdown: Shutdown,
-    mut response: Response,
+    mut response: Body,
+    part: Option<Arc<RangePart>>,
+    mut hasher: Option<ChecksumHasher>,
+    decompress: Option<Decompress>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    handle: Option<FetchHandle>,
+    report: &ReportRecorder,
+    abandoned: &AtomicBool,
 ) -> Result<(Arc<Path>, File), crate::Error> {
     let mut read_total = 0;
+    let mut transferred = 0;
 
-    let mut now = Instant::now();
+    let started = Instant::now();
+    let mut now = started;
 
     let update_progress = |progress: usize| {
         fetcher.send(|| {
@@ -155,12 +220,50 @@ async fn fetch_loop<Data: Send + Sync + 'static, Response: AsyncRead + Unpin>(
 
     let mut buffer = vec![0u8; 8192];
 
+    // The checksum is computed from the bytes which are written, if they are decompressed.
+    let mut decoded_hasher = match decompress {
+        Some(decompress) if decompress.checksum_decompressed => hasher.take(),
+        _ => None,
+    };
+
+    let format = decompress.and_then(|decompress| decompress.format);
+
+    // Errors of the decoder are kept apart from errors writing the file, such as a full
+    // disk, which are not the fault of the mirror.
+    let write_error = |why: io::Error| match format {
+        Some(_) if decompress::is_corrupt(&why) => Error::Decompress(why),
+        _ => Error::Write(why),
+    };
+
     let fetch_loop = async {
+        // Bytes which were fetched by an earlier attempt are hashed first.
+        if let Some(hasher) = hasher.as_mut() {
+            file.seek(SeekFrom::Start(0)).map_err(Error::Write)?;
+            hasher
+                .update_from(&mut file, &mut buffer)
+                .map_err(|why| Error::Checksum(why.into()))?;
+        }
+
+        let output = HashingWriter {
+            inner: &mut file,
+            hasher: decoded_hasher.as_mut(),
+        };
+
+        let mut output: Box<dyn Decoder + Send + '_> = match format {
+            Some(format) => decompress::decoder(format, output)?,
+            None => Box::new(output),
+        };
+
         loop {
             if shutdown.shutdown_started() || shutdown.shutdown_completed() {
                 return Err(Error::Canceled);
             }
 
+            // The connection is closed while paused, and reopened once resumed.
+            if fetcher.is_paused() || handle.as_ref().is_some_and(FetchHandle::is_paused) {
+                return Err(Error::Paused);
+            }
+
             let bytes_read = async { response.read(&mut buffer).await.map_err(Error::Read) };
 
             let read = match fetcher.timeout {
@@ -169,12 +272,47 @@ async fn fetch_loop<Data: Send + Sync + 'static, Response: AsyncRead + Unpin>(
             }?;
 
             if read == 0 {
+                if part.as_ref().is_some_and(|part| !part.is_complete()) {
+                    let why = io::Error::new(io::ErrorKind::UnexpectedEof, "range ended early");
+                    return Err(Error::Read(why));
+                }
+
                 break;
             }
 
+            // Waits until the bytes read are within the fetcher's and the source's limits.
+            let wait = fetcher.rate_limiter.take(read as u64).max(
+                rate_limit
+                    .as_ref()
+                    .map_or(Duration::ZERO, |limit| limit.take(read as u64)),
+            );
+
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+
+            if abandoned.load(Ordering::SeqCst) {
+                return Err(Error::Canceled);
+            }
+
+            // A part only writes up to its end, which may have been stolen by another.
+            let read = match part.as_ref() {
+                Some(part) => part.advance(read as u64) as usize,
+                None => read,
+            };
+
             read_total += read;
+            transferred += read as u64;
 
-            file.write_all(&buffer[..read]).map_err(Error::Write)?;
+            output.write_all(&buffer[..read]).map_err(write_error)?;
+
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..read]);
+            }
+
+            if let Some(part) = part.as_ref() {
+                part.commit(read as u64);
+            }
 
             if now.elapsed().as_millis() as u64 > fetcher.progress_interval {
                 update_progress(read_total);
@@ -183,16 +321,35 @@ async fn fetch_loop<Data: Send + Sync + 'static, Response: AsyncRead + Unpin>(
                 read_total = 0;
             }
 
-            attempts.store(0, Ordering::SeqCst);
+            attempts.progress();
+
+            if part.as_ref().is_some_and(|part| part.is_complete()) {
+                break;
+            }
         }
 
-        Ok(())
+        output.finish().map_err(write_error)
     };
 
     let fetch_result = fetch_loop.await;
+
+    report.transferred(transferred);
+
+    match fetch_result {
+        Ok(()) => fetcher
+            .mirror_health
+            .record_transfer(uri, transferred, started.elapsed()),
+        Err(Error::TimedOut) | Err(Error::Read(_)) => fetcher.mirror_health.record_failure(uri),
+        Err(_) => (),
+    }
+
     let seek_result = file.seek(SeekFrom::Start(0)).map_err(Error::Write);
 
-    let result = fetch_result.and(seek_result);
+    let mut result = fetch_result.and(seek_result).map(|_| ());
+
+    if let (Ok(()), Some(hasher)) = (&result, hasher.or(decoded_hasher)) {
+        result = hasher.finish().map_err(Error::Checksum);
+    }
 
     if result.is_ok() && read_total != 0 {
         update_progress(read_total);
@@ -1,93 +1,262 @@
 // Copyright 2021-2022 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
+use crate::control::{ControlFile, PartRecord};
 use crate::get::FetchLocation;
+use crate::scheduler::{RangePart, RangeScheduler};
 use crate::*;
-use std::sync::atomic::AtomicU16;
+use futures::future::{self, Either};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+
+/// A part which has been fetched, and the file that it was written to.
+type FetchedPart = (Arc<RangePart>, Arc<Path>, File);
 
 #[allow(clippy::too_many_arguments)]
-pub async fn get_many<Data: Send + Sync + 'static>(
-    fetcher: Arc<Fetcher<Data>>,
+pub async fn get_many<Data: Send + Sync + 'static, C: HttpBackend>(
+    fetcher: Arc<Fetcher<Data, C>>,
+    dest: Arc<Path>,
     to: Arc<Path>,
     uris: Arc<[Box<str>]>,
     offset: u64,
     length: u64,
     modified: Option<HttpDate>,
     extra: Arc<Data>,
-    attempts: Arc<AtomicU16>,
+    attempts: Arc<Attempts>,
+    options: FetchOptions,
 ) -> Result<(), Error> {
     let shutdown = fetcher.shutdown.clone();
     let parent = to.parent().ok_or(Error::Parentless)?.to_owned();
     let filename = to.file_name().ok_or(Error::Nameless)?.to_owned();
 
-    let mut buf = [0u8; 20];
+    let part_path = |id: usize| -> Arc<Path> {
+        let mut buf = [0u8; 20];
+        let mut new_filename = filename.to_os_string();
+        new_filename.push([".part", id.numtoa_str(10, &mut buf)].concat());
+        Arc::from(parent.join(new_filename))
+    };
 
-    let FetchLocation { file, .. } = FetchLocation::create(to.clone(), offset != 0).await?;
+    let positional = fetcher.positional_writes;
+    let control = Arc::new(ControlFile::new(&to).positional(positional));
+    let mut offset = offset;
 
-    let concurrent_fetches = fetcher.connections_per_file as usize;
+    // Continue from the parts of a previous attempt, if they were for the same file.
+    let resumed = control
+        .load(length, modified)
+        .and_then(|parts| resume_parts(parts, &mut offset, length, positional, part_path));
 
-    let to_ = to.clone();
-    let parts =
-        stream::iter(range::generate(length, fetcher.max_part_size.into(), offset).enumerate())
-            // Generate a future for fetching each part that a range describes.
-            .map(move |(partn, (range_start, range_end))| {
-                let uri = uris[partn % uris.len()].clone();
+    let append = offset != 0 || (positional && resumed.is_some());
+    let FetchLocation { file, .. } = FetchLocation::create(to.clone(), append).await?;
 
-                let part_path = {
-                    let mut new_filename = filename.to_os_string();
-                    new_filename.push(&[".part", partn.numtoa_str(10, &mut buf)].concat());
-                    parent.join(new_filename)
-                };
+    if positional {
+        file.set_len(length).map_err(Error::Write)?;
+    }
+
+    if fetcher.preallocate {
+        crate::space::preallocate(&file, length, &to)?;
+    }
+
+    // The bytes which were fetched by previous attempts, and will not be fetched again.
+    let mut previously_fetched = offset;
+
+    let scheduler = match resumed {
+        Some(parts) => {
+            // Bytes of the destination which are also in a part file are fetched again.
+            if !positional {
+                file.set_len(offset).map_err(Error::Write)?;
+            }
+
+            let fetched: u64 = parts.iter().map(|(part, pos)| pos - part.start).sum();
+            previously_fetched = offset + fetched;
+            info!("resuming {} parts of {}", parts.len(), dest.display());
+
+            RangeScheduler::resume(parts.into_iter())
+        }
+        None => {
+            remove_parts(&to).await;
+
+            RangeScheduler::new(range::generate(
+                length,
+                fetcher.max_part_size.into(),
+                offset,
+            ))
+        }
+    };
+
+    if previously_fetched != 0 {
+        options.report.resumed(previously_fetched);
+
+        let event = FetchEvent::Resumed(previously_fetched);
+        fetcher.send(|| (dest.clone(), extra.clone(), event));
+        let event = FetchEvent::Progress(previously_fetched);
+        fetcher.send(|| (dest.clone(), extra.clone(), event));
+    }
+
+    let scheduler = Arc::new(scheduler);
+
+    control
+        .save(length, modified, &scheduler.layout())
+        .map_err(Error::Write)?;
+
+    let (parts_tx, parts_rx) = mpsc::unbounded_channel();
+
+    // The checksum is computed from the whole file, rather than from each part.
+    let part_options = FetchOptions {
+        checksum: None,
+        ..options.clone()
+    };
 
-                if part_path.exists() {
-                    let _ = std::fs::remove_file(&part_path);
+    // Each connection fetches ranges from the scheduler until none are left.
+    let connections = (0..fetcher.connections_per_file).map(|_| {
+        let fetcher = fetcher.clone();
+        let scheduler = scheduler.clone();
+        let control = control.clone();
+        let parts_tx = parts_tx.clone();
+        let dest = dest.clone();
+        let to = to.clone();
+        let uris = uris.clone();
+        let extra = extra.clone();
+        let attempts = attempts.clone();
+        let part_options = part_options.clone();
+
+        async move {
+            while let Some(part) = scheduler.next() {
+                if part.stolen {
+                    control
+                        .save(length, modified, &scheduler.layout())
+                        .map_err(Error::Write)?;
                 }
 
-                let fetcher = fetcher.clone();
-                let to = to_.clone();
-                let extra = extra.clone();
-                let attempts = attempts.clone();
+                // Every byte of a positional part is already in the destination.
+                if positional && part.is_complete() {
+                    continue;
+                }
 
-                let builder = match &fetcher.client {
-                    #[cfg(feature = "isahc")]
-                    Client::Isahc(_) => RequestBuilder::Http(HttpRequest::get(&*uri)),
-                    #[cfg(feature = "reqwest")]
-                    Client::Reqwest(client) => RequestBuilder::Reqwest(client.get(&*uri)),
-                };
+                let path = part_path(part.id);
 
-                async move {
-                    let range = range::to_string(range_start, Some(range_end));
-                    let part_path: Arc<Path> = Arc::from(part_path);
-
-                    let request = match builder {
-                        #[cfg(feature = "isahc")]
-                        RequestBuilder::Http(inner) => {
-                            RequestBuilder::Http(inner.header("range", range.as_str()))
-                        }
-                        #[cfg(feature = "reqwest")]
-                        RequestBuilder::Reqwest(inner) => {
-                            RequestBuilder::Reqwest(inner.header("range", range.as_str()))
-                        }
-                    };
-
-                    crate::get(
-                        fetcher.clone(),
-                        request,
-                        FetchLocation::create(part_path.clone(), false).await?,
-                        to.clone(),
-                        extra.clone(),
-                        attempts.clone(),
+                let result = if part.is_complete() {
+                    FetchLocation::create(path.clone(), true).await.and_then(
+                        |FetchLocation { mut file, dest }| {
+                            file.seek(SeekFrom::Start(0)).map_err(Error::Write)?;
+                            Ok((dest, file))
+                        },
                     )
-                    .await
+                } else {
+                    // Steer each part towards the healthiest and least busy mirror.
+                    let (uri, _active) = fetcher.mirror_health.select(&uris);
+
+                    // Both events of a part begin where this connection started from.
+                    let start = part.position();
+
+                    let range = range::to_string(start, Some(part.end() - 1));
+                    let request = HttpRequest::get(&*uri).header("range", range.as_str());
+
+                    fetcher.send(|| {
+                        let event = FetchEvent::PartStarted(start..part.end(), uri.clone());
+                        (dest.clone(), extra.clone(), event)
+                    });
+
+                    let result = async {
+                        let location = if positional {
+                            FetchLocation::open_at(to.clone(), part.position()).await?
+                        } else {
+                            let resuming = part.position() != part.start;
+                            FetchLocation::create(path.clone(), resuming).await?
+                        };
+
+                        crate::get(
+                            fetcher.clone(),
+                            request,
+                            location,
+                            dest.clone(),
+                            extra.clone(),
+                            attempts.clone(),
+                            Some(part.clone()),
+                            part_options.clone(),
+                        )
+                        .await
+                    }
+                    .await;
+
+                    if result.is_ok() {
+                        fetcher.send(|| {
+                            let event = FetchEvent::PartFinished(start..part.end(), uri);
+                            (dest.clone(), extra.clone(), event)
+                        });
+                    }
+
+                    result
+                };
+
+                match result {
+                    Ok(_) if positional => {
+                        control
+                            .save(length, modified, &scheduler.layout())
+                            .map_err(Error::Write)?;
+                    }
+                    Ok((path, file)) => {
+                        let _ = parts_tx.send(Ok((part, path, file)));
+                    }
+                    Err(why) => {
+                        let _ = parts_tx.send(Err(Error::Canceled));
+                        return Err(why);
+                    }
                 }
-            })
-            // Ensure that only this many connections are happenning concurrently at a
-            // time
-            .buffered(concurrent_fetches);
+            }
+
+            Ok::<(), Error>(())
+        }
+    });
+
+    let connections: Vec<_> = connections.collect();
+    drop(parts_tx);
+
+    let fetches = future::try_join_all(connections);
 
     let _shutdown_token = shutdown.delay_shutdown_token();
 
-    concatenator(file, parts, to.clone(), shutdown.clone()).await?;
+    if positional {
+        drop(parts_rx);
+
+        if let Err(why) = fetches.await {
+            // Record how far each part got, so that a later attempt continues from there.
+            let _ = control.save(length, modified, &scheduler.layout());
+            return Err(why);
+        }
+
+        // Parts were written out of order, so the checksum is computed afterwards.
+        if let Some(checksum) = options.checksum {
+            checksum
+                .validate_file(to.clone())
+                .await
+                .map_err(Error::Checksum)?;
+        }
+    } else {
+        let parts = Box::pin(in_order(parts_rx, offset));
+        let concatenate =
+            concatenator_checked(file, parts, to.clone(), shutdown.clone(), options.checksum);
+
+        futures::pin_mut!(fetches);
+        futures::pin_mut!(concatenate);
+
+        // Dropping the connections after the concatenator fails cancels them.
+        match future::select(fetches, concatenate).await {
+            Either::Left((Ok(_), concatenate)) => concatenate.await?,
+            Either::Left((Err(why), concatenate)) => {
+                let _ = concatenate.await;
+                return Err(why);
+            }
+            // A connection which failed canceled the concatenator, so its error is returned.
+            Either::Right((Err(Error::Canceled), fetches)) => {
+                fetches.await?;
+                return Err(Error::Canceled);
+            }
+            Either::Right((result, _)) => result?,
+        }
+    }
+
+    control.remove();
 
     if let Some(modified) = modified {
         crate::time::update_modified(&to, modified)?;
@@ -95,3 +264,379 @@ pub async fn get_many<Data: Send + Sync + 'static>(
 
     Ok(())
 }
+
+/// Yields fetched parts in the order that they appear in the file.
+///
+/// Parts may finish in any order, so those which finish early are held back until
+/// every part before them has been yielded.
+fn in_order(
+    parts: mpsc::UnboundedReceiver<Result<FetchedPart, Error>>,
+    offset: u64,
+) -> impl Stream<Item = Result<(Arc<Path>, File), Error>> + Send {
+    let finished = BTreeMap::new();
+
+    stream::unfold(
+        (parts, offset, finished),
+        |(mut parts, mut next, mut finished)| async move {
+            loop {
+                if let Some((part, path, file)) = finished.remove(&next) {
+                    let part: Arc<RangePart> = part;
+                    next = part.end();
+                    return Some((Ok((path, file)), (parts, next, finished)));
+                }
+
+                match parts.recv().await? {
+                    Ok((part, path, file)) => {
+                        finished.insert(part.start, (part, path, file));
+                    }
+                    Err(why) => return Some((Err(why), (parts, next, finished))),
+                }
+            }
+        },
+    )
+}
+
+/// Validates the parts recorded by a previous attempt against the files on disk.
+///
+/// The parts must cover every byte from `offset` to `length`. Parts which were already
+/// concatenated into the destination are skipped, and `offset` is moved back to the
+/// start of a part if it was only partially concatenated. The progress of each part
+/// is the length of its part file, or its recorded position if it was written
+/// directly into the destination.
+fn resume_parts(
+    mut parts: Vec<PartRecord>,
+    offset: &mut u64,
+    length: u64,
+    positional: bool,
+    part_path: impl Fn(usize) -> Arc<Path>,
+) -> Option<Vec<(PartRecord, u64)>> {
+    parts.sort_by_key(|part| part.start);
+    parts.retain(|part| part.end > *offset);
+
+    let first = parts.first()?;
+    if first.start > *offset {
+        return None;
+    }
+
+    // The offset is only moved back once the parts are known to be usable.
+    let start = first.start;
+
+    let mut expected = start;
+    for part in &parts {
+        if part.start != expected || part.end < part.start {
+            return None;
+        }
+
+        expected = part.end;
+    }
+
+    if expected != length {
+        return None;
+    }
+
+    *offset = start;
+
+    Some(
+        parts
+            .into_iter()
+            .map(|part| {
+                let fetched = if positional {
+                    part.position.saturating_sub(part.start)
+                } else {
+                    std::fs::metadata(&*part_path(part.id)).map_or(0, |m| m.len())
+                };
+
+                (part, part.start + fetched.min(part.end - part.start))
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, block_on, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use http::Method;
+    use std::time::SystemTime;
+
+    const MIB: u64 = 1024 * 1024;
+    const URI: &str = "http://mirror/file";
+
+    fn record(id: usize, start: u64, end: u64, position: u64) -> PartRecord {
+        PartRecord {
+            id,
+            start,
+            end,
+            position,
+        }
+    }
+
+    /// Validates the parts against part files of the given lengths in `dir`.
+    fn resume(
+        dir: &Path,
+        parts: Vec<PartRecord>,
+        offset: &mut u64,
+        positional: bool,
+        fetched: &[(usize, usize)],
+    ) -> Option<Vec<(u64, u64)>> {
+        let part_path = |id: usize| -> Arc<Path> { Arc::from(dir.join(id.to_string())) };
+
+        for &(id, length) in fetched {
+            std::fs::write(part_path(id), vec![0u8; length]).unwrap();
+        }
+
+        let parts = resume_parts(parts, offset, 300, positional, part_path)?;
+        Some(
+            parts
+                .iter()
+                .map(|(part, position)| (part.start, *position))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parts_continue_from_their_part_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = vec![
+            record(1, 100, 200, 0),
+            record(0, 0, 100, 0),
+            record(2, 200, 300, 0),
+        ];
+
+        let mut offset = 0;
+        let fetched = [(0, 100), (1, 30), (2, 500)];
+        let resumed = resume(dir.path(), parts, &mut offset, false, &fetched);
+
+        // A part file longer than its part is not read past the end of the part.
+        assert_eq!(resumed, Some(vec![(0, 100), (100, 130), (200, 300)]));
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn positional_parts_continue_from_their_recorded_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = vec![record(0, 0, 150, 150), record(1, 150, 300, 170)];
+
+        let mut offset = 0;
+        let resumed = resume(dir.path(), parts, &mut offset, true, &[(1, 5)]);
+
+        assert_eq!(resumed, Some(vec![(0, 150), (150, 170)]));
+    }
+
+    #[test]
+    fn partly_concatenated_parts_are_fetched_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = vec![
+            record(0, 0, 100, 0),
+            record(1, 100, 200, 0),
+            record(2, 200, 300, 0),
+        ];
+
+        // The destination ends in the middle of the second part.
+        let mut offset = 150;
+        let resumed = resume(dir.path(), parts, &mut offset, false, &[(1, 100)]);
+
+        assert_eq!(resumed, Some(vec![(100, 200), (200, 200)]));
+        assert_eq!(offset, 100);
+    }
+
+    #[test]
+    fn parts_which_do_not_cover_the_file_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let gap = vec![record(0, 0, 100, 0), record(1, 150, 300, 0)];
+        let short = vec![record(0, 0, 100, 0), record(1, 100, 250, 0)];
+        let overlap = vec![record(0, 0, 150, 0), record(1, 100, 300, 0)];
+        let after = vec![record(1, 150, 300, 0)];
+
+        for parts in [gap, short, overlap, after] {
+            let mut offset = 100;
+            assert_eq!(resume(dir.path(), parts, &mut offset, false, &[]), None);
+            assert_eq!(offset, 100);
+        }
+
+        let mut offset = 300;
+        assert_eq!(
+            resume(dir.path(), Vec::new(), &mut offset, false, &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn interrupted_fetches_continue_from_their_parts() {
+        let modified = HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30));
+        let file = MockFile::new(contents(256 * 1024)).last_modified(modified);
+
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, file);
+        backend.fault(URI, Some(Method::GET), Fault::Disconnect { after: 10_000 });
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = |backend| {
+            support::fetcher(backend)
+                .connections_per_file(2)
+                .max_part_size(64 * 1024)
+        };
+
+        // The first fetch gives up as soon as its connection is lost.
+        let policy = RetryPolicy::default().on(ErrorClass::Connection, RetryDecision::Abort);
+        let interrupted = fetcher(&backend).retry_policy(policy);
+        assert!(support::fetch(interrupted, &[URI], &dest).is_err());
+
+        let requests = backend.requests().len();
+        let report = support::fetch(fetcher(&backend), &[URI], &dest).unwrap();
+
+        assert_eq!(report.outcome, FetchOutcome::Resumed);
+        assert!(report.resumed >= 10_000);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(256 * 1024));
+
+        // The interrupted part is requested from where it was lost.
+        let ranges: Vec<_> = backend.requests()[requests..]
+            .iter()
+            .filter_map(|request| request.headers.get("range").cloned())
+            .collect();
+
+        assert!(ranges.iter().any(|range| range == "bytes=10000-65535"));
+        assert!(!ranges.iter().any(|range| range == "bytes=0-65535"));
+    }
+
+    fn finished(part: &Arc<RangePart>) -> Result<FetchedPart, Error> {
+        let path: Arc<Path> = Arc::from(Path::new(&part.id.to_string()));
+        Ok((part.clone(), path, tempfile::tempfile().unwrap()))
+    }
+
+    #[test]
+    fn in_order_follows_parts_which_were_split() {
+        let scheduler = RangeScheduler::new(vec![(0, MIB - 1), (MIB, 2 * MIB - 1)].into_iter());
+
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        second.advance(1024);
+        let stolen = scheduler.next().unwrap();
+
+        // The end of the first part shrank to the start of the range stolen from it.
+        assert_eq!(first.end(), stolen.start);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        for part in &[&second, &stolen, &first] {
+            tx.send(finished(part)).unwrap();
+        }
+        drop(tx);
+
+        let paths: Vec<_> = block_on(in_order(rx, 0).collect::<Vec<_>>())
+            .into_iter()
+            .map(|result| result.unwrap().0)
+            .collect();
+
+        let expected: Vec<Arc<Path>> = ["0", "2", "1"]
+            .iter()
+            .map(|id| Arc::from(Path::new(id)))
+            .collect();
+
+        assert_eq!(paths, expected);
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn positional_parts_are_written_into_the_destination() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(256 * 1024)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = support::fetcher(&backend)
+            .connections_per_file(3)
+            .max_part_size(32 * 1024)
+            .positional_writes(true);
+
+        support::fetch(fetcher, &[URI], &dest).unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(256 * 1024));
+        assert_eq!(file_names(dir.path()), ["file"]);
+    }
+
+    #[test]
+    fn interrupted_positional_fetches_continue_from_their_positions() {
+        let modified = HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30));
+        let file = MockFile::new(contents(256 * 1024)).last_modified(modified);
+
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, file);
+        backend.fault(URI, Some(Method::GET), Fault::Disconnect { after: 10_000 });
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = |backend| {
+            support::fetcher(backend)
+                .connections_per_file(2)
+                .max_part_size(64 * 1024)
+                .positional_writes(true)
+        };
+
+        let policy = RetryPolicy::default().on(ErrorClass::Connection, RetryDecision::Abort);
+        let interrupted = fetcher(&backend).retry_policy(policy);
+        assert!(support::fetch(interrupted, &[URI], &dest).is_err());
+
+        // Parts were written into the partial file, rather than into part files.
+        let names = file_names(dir.path());
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| !name.contains(".part")));
+
+        let requests = backend.requests().len();
+        let report = support::fetch(fetcher(&backend), &[URI], &dest).unwrap();
+
+        assert_eq!(report.outcome, FetchOutcome::Resumed);
+        assert!(report.resumed >= 10_000);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(256 * 1024));
+        assert_eq!(file_names(dir.path()), ["file"]);
+
+        let resumed = backend.requests()[requests..].iter().any(|request| {
+            request
+                .headers
+                .get("range")
+                .is_some_and(|range| range == "bytes=10000-65535")
+        });
+
+        assert!(resumed);
+    }
+
+    #[test]
+    fn interrupted_fetches_without_a_modification_time_start_over() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(256 * 1024)));
+        backend.fault(URI, Some(Method::GET), Fault::Disconnect { after: 10_000 });
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = |backend| {
+            support::fetcher(backend)
+                .connections_per_file(2)
+                .max_part_size(64 * 1024)
+        };
+
+        let policy = RetryPolicy::default().on(ErrorClass::Connection, RetryDecision::Abort);
+        let interrupted = fetcher(&backend).retry_policy(policy);
+        assert!(support::fetch(interrupted, &[URI], &dest).is_err());
+
+        // The parts may be those of another file of the same length.
+        let report = support::fetch(fetcher(&backend), &[URI], &dest).unwrap();
+
+        assert_eq!(report.outcome, FetchOutcome::Fetched);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(256 * 1024));
+        assert_eq!(file_names(dir.path()), ["file"]);
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use futures::future::{self, Either};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// Controls the fetch of a single `Source`, while it is queued or being fetched.
+///
+/// Every source is created with its own handle, which may be cloned and kept after the
+/// source is given to the fetcher. The fetch may be canceled, or paused and resumed.
+///
+/// ```
+/// use async_fetcher::Source;
+/// use std::{path::Path, sync::Arc};
+///
+/// let source = Source::builder(Arc::from(Path::new("file")), "http://localhost/file".into())
+///     .build();
+///
+/// let handle = source.handle.clone();
+/// handle.cancel();
+/// assert!(source.handle.is_canceled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FetchHandle {
+    inner: Arc<HandleState>,
+}
+
+#[derive(Debug, Default)]
+struct HandleState {
+    canceled: AtomicBool,
+    notify: Notify,
+    pause: Pause,
+}
+
+/// Whether fetches are paused, which may be waited on to change.
+#[derive(Debug, Default)]
+pub(crate) struct Pause {
+    paused: AtomicBool,
+    notify: Notify,
+}
+
+impl Pause {
+    pub fn set(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Completes when the state next changes, if created before checking the state.
+    pub fn changed(&self) -> Notified<'_> {
+        self.notify.notified()
+    }
+}
+
+impl FetchHandle {
+    /// Aborts the fetch, and removes the data that it has fetched so far.
+    ///
+    /// The fetch yields `Error::Canceled`, without affecting any other fetch.
+    pub fn cancel(&self) {
+        self.inner.canceled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.inner.canceled.load(Ordering::SeqCst)
+    }
+
+    /// Stops reading the fetch until it is resumed, keeping the data fetched so far.
+    ///
+    /// The connections of the fetch are closed, and reopened with range requests for the
+    /// remaining data once it is resumed.
+    pub fn pause(&self) {
+        self.inner.pause.set(true);
+    }
+
+    /// Continues a paused fetch.
+    pub fn resume(&self) {
+        self.inner.pause.set(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.pause.is_paused()
+    }
+
+    pub(crate) fn pause_state(&self) -> &Pause {
+        &self.inner.pause
+    }
+
+    /// Waits until the fetch is canceled.
+    async fn canceled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+
+            if self.is_canceled() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Runs a future until it completes, or until the fetch is canceled.
+    pub(crate) async fn until_canceled<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        if self.is_canceled() {
+            return None;
+        }
+
+        let canceled = self.canceled();
+
+        futures::pin_mut!(canceled);
+        futures::pin_mut!(future);
+
+        match future::select(future, canceled).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::support::{self, block_on, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use crate::{Error, Source};
+    use futures::{future, StreamExt};
+    use http::Method;
+    use httpdate::HttpDate;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    const URI: &str = "http://mirror/file";
+
+    fn size(path: &Path) -> u64 {
+        std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+    }
+
+    /// Pauses a fetch of `file` while its body stalls, and checks that it continues from
+    /// the data which was fetched before it was paused.
+    fn pause_and_resume(file: MockFile) {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, file);
+
+        let stall = Fault::StallBody {
+            after: 10_000,
+            duration: Duration::from_millis(100),
+        };
+        backend.fault(URI, Some(Method::GET), stall);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        let partial = dir.path().join("file.fetching");
+
+        let source = Source::builder(Arc::from(dest.as_path()), URI.into()).build();
+        let handle = source.handle.clone();
+
+        let fetcher = support::fetcher(&backend).build();
+        let inputs = futures::stream::iter(vec![(source, Arc::new(()))]);
+        let fetch = fetcher.stream_from(inputs, 1).collect::<Vec<_>>();
+
+        let control = async {
+            while size(&partial) < 10_000 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+
+            handle.pause();
+
+            // The connection is closed while the fetch is paused.
+            while backend.open_connections() != 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+
+            let requests = backend.requests().len();
+            let kept = size(&partial);
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(backend.requests().len(), requests);
+
+            handle.resume();
+            (requests, kept)
+        };
+
+        let fetch = future::join(fetch, control);
+        let output = block_on(async { tokio::time::timeout(Duration::from_secs(10), fetch).await });
+        let (results, (requests, kept)) = output.expect("fetch was not resumed");
+
+        assert!(results[0].2.is_ok());
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(64 * 1024));
+        assert!((10_000..64 * 1024).contains(&kept));
+
+        let resumed = backend.requests()[requests..]
+            .iter()
+            .find(|request| request.method == Method::GET)
+            .and_then(|request| request.headers.get("range").cloned())
+            .expect("resumed without a range");
+
+        let range = resumed.to_str().unwrap();
+        assert!(range.starts_with(&format!("bytes={}-", kept)), "{}", range);
+    }
+
+    #[test]
+    fn paused_fetches_continue_from_the_data_fetched() {
+        let modified = HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30));
+        pause_and_resume(MockFile::new(contents(64 * 1024)).last_modified(modified));
+    }
+
+    #[test]
+    fn paused_fetches_without_a_modification_time_continue_from_the_data_fetched() {
+        pause_and_resume(MockFile::new(contents(64 * 1024)));
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+
+        names.sort();
+        names
+    }
+
+    /// Cancels a fetch once `started` is true of its directory, while its body stalls.
+    fn cancel(connections: u16, started: impl Fn(&Path) -> bool) {
+        let modified = HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30));
+        let file = MockFile::new(contents(256 * 1024)).last_modified(modified);
+
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, file);
+
+        for _ in 0..connections {
+            let stall = Fault::StallBody {
+                after: 8192,
+                duration: Duration::from_millis(200),
+            };
+            backend.fault(URI, Some(Method::GET), stall);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let source = Source::builder(Arc::from(dest.as_path()), URI.into()).build();
+        let handle = source.handle.clone();
+
+        let fetcher = support::fetcher(&backend)
+            .connections_per_file(connections)
+            .max_part_size(32 * 1024)
+            .build();
+
+        let inputs = futures::stream::iter(vec![(source, Arc::new(()))]);
+        let fetch = fetcher.stream_from(inputs, 1).collect::<Vec<_>>();
+
+        let control = async {
+            while !started(dir.path()) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+
+            handle.cancel();
+        };
+
+        let (output, requests) = block_on(async {
+            let fetch = future::join(fetch, control);
+            let output = tokio::time::timeout(Duration::from_secs(10), fetch).await;
+            let requests = backend.requests().len();
+
+            // A connection is closed once its stalled read returns.
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            (output, requests)
+        });
+
+        let (results, ()) = output.expect("fetch was not canceled");
+        assert!(matches!(results[0].2, Err(Error::Canceled)));
+
+        // The transfer stopped, and the data that it fetched was removed.
+        assert_eq!(backend.requests().len(), requests);
+        assert_eq!(backend.open_connections(), 0);
+        assert!(file_names(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn canceled_fetches_remove_their_partial_file() {
+        cancel(1, |dir| size(&dir.join("file.fetching")) >= 8192);
+    }
+
+    #[test]
+    fn canceled_fetches_remove_their_parts() {
+        cancel(2, |dir| {
+            dir.join("file.fetching.fetch-state").exists()
+                && size(&dir.join("file.fetching.part0")) >= 8192
+        });
+    }
+}
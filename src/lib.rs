@@ -8,9 +8,12 @@
 //! - Use multiple concurrent connections per file.
 //! - Use mirrors for concurrent connections.
 //! - Resume a download which has been interrupted.
-//! - Progress events for fetches
+//! - Progress events for fetches, and a tracker of their transfer rates and ETAs
+//! - Fetch into any `AsyncWrite`, or into memory
+//! - Decompress gzip, xz, bzip2 and zstd files while they are fetched
+//! - Pluggable HTTP clients through the `HttpBackend` trait
 //!
-//! ```
+//! ```ignore
 //! let (events_tx, events_rx) = tokio::sync::mpsc::unbounded_channel();
 //!
 //! let shutdown = async_shutdown::Shutdown::new();
@@ -24,6 +27,8 @@
 //!     .events(events_tx)
 //!     // Maximum number of retry attempts.
 //!     .retries(3)
+//!     // Back off exponentially between attempts, with up to 30s between them.
+//!     .retry_policy(RetryPolicy::default().max_delay(Duration::from_secs(30)))
 //!     // Cancels the fetching process when a shutdown is triggered.
 //!     .shutdown(shutdown)
 //!     // How long to wait before aborting a download that hasn't progressed.
@@ -45,24 +50,62 @@ extern crate log;
 extern crate thiserror;
 
 pub mod iface;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
+mod backend;
 mod checksum;
 mod checksum_system;
 mod concatenator;
+mod connections;
+mod context;
+mod control;
+mod decompress;
+mod failover;
+mod finalize;
 mod get;
 mod get_many;
+mod handle;
+mod mirrors;
+mod probe;
+mod progress;
+mod queue;
 mod range;
+mod rate_limit;
+mod report;
+mod retry;
+mod scheduler;
+mod sink;
 mod source;
+mod space;
 mod time;
 mod utils;
 
+pub use self::backend::*;
 pub use self::checksum::*;
 pub use self::checksum_system::*;
 pub use self::concatenator::*;
+pub use self::context::ErrorContext;
+pub use self::decompress::{Compression, Decompress};
+pub use self::finalize::Durability;
+pub use self::handle::*;
+pub use self::mirrors::*;
+pub use self::progress::*;
+pub use self::rate_limit::*;
+pub use self::report::{FetchOutcome, FetchReport};
+pub use self::retry::*;
 pub use self::source::*;
 
-use self::get::{get, FetchLocation};
+use self::connections::{HostConnections, PermitBody};
+use self::control::ControlFile;
+use self::failover::Failover;
+use self::finalize::{finalize, staging_path};
+use self::get::{get, FetchLocation, GetRequest};
 use self::get_many::get_many;
+use self::handle::Pause;
+use self::queue::prioritized;
+use self::source::FetchOptions;
+use self::space::{ensure_space, preallocate};
 use self::time::{date_as_timestamp, update_modified};
 use async_shutdown::Shutdown;
 use futures::{
@@ -70,38 +113,33 @@ use futures::{
     stream::{self, StreamExt},
 };
 
-use http::StatusCode;
-#[cfg(feature = "isahc")]
-use http::{request::Builder as HttpBuilder, Request as HttpRequest};
+use http::{Request as HttpRequest, Response as HttpResponse, StatusCode};
 use httpdate::HttpDate;
 #[cfg(feature = "isahc")]
 use isahc::config::RedirectPolicy;
 #[cfg(feature = "isahc")]
-use isahc::AsyncBody;
-#[cfg(feature = "isahc")]
-use isahc::{HttpClient as IsahcClient, Response as IsahcResponse};
+use isahc::HttpClient as IsahcClient;
 use numtoa::NumToA;
-#[cfg(feature = "reqwest")]
+#[cfg(all(feature = "reqwest", not(feature = "isahc")))]
 use reqwest::redirect::Policy;
-#[cfg(feature = "reqwest")]
-use reqwest::{
-    Client as ReqwestClient, RequestBuilder as ReqwestBuilder, Response as ReqwestResponse,
-};
+#[cfg(all(feature = "reqwest", not(feature = "isahc")))]
+use reqwest::Client as ReqwestClient;
 
-use std::sync::atomic::Ordering;
 use std::{
+    collections::HashSet,
     fmt::Debug,
     io,
+    ops::Range,
     path::Path,
     pin::Pin,
-    sync::{atomic::AtomicU16, Arc},
-    time::{Duration, UNIX_EPOCH},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::fs;
 use tokio::sync::mpsc;
 
 /// The result of a fetched task from a stream of input sources.
-pub type AsyncFetchOutput<Data> = (Arc<Path>, Arc<Data>, Result<(), Error>);
+pub type AsyncFetchOutput<Data> = (Arc<Path>, Arc<Data>, Result<FetchReport, Error>);
 
 /// A channel for sending `FetchEvent`s to.
 pub type EventSender<Data> = mpsc::UnboundedSender<(Arc<Path>, Data, FetchEvent)>;
@@ -109,6 +147,8 @@ pub type EventSender<Data> = mpsc::UnboundedSender<(Arc<Path>, Data, FetchEvent)
 /// An error from the asynchronous file fetcher.
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("every mirror failed: {}", context::join(_0))]
+    AllMirrorsFailed(Vec<Error>),
     #[error("task was canceled")]
     Canceled,
     #[cfg(feature = "isahc")]
@@ -117,10 +157,20 @@ pub enum Error {
     #[cfg(feature = "reqwest")]
     #[error("http client error")]
     ReqwestClient(#[source] reqwest::Error),
+    #[error("http backend error")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("fetched file does not match its checksum")]
+    Checksum(#[source] ChecksumError),
     #[error("unable to concatenate fetched parts")]
     Concatenate(#[source] io::Error),
+    #[error("{}", _0)]
+    Context(Box<ErrorContext>, #[source] Box<Error>),
+    #[error("unable to decompress fetched file")]
+    Decompress(#[source] io::Error),
     #[error("unable to create file")]
     FileCreate(#[source] io::Error),
+    #[error("not enough disk space: {} bytes required, {} bytes available", _0, _1)]
+    InsufficientSpace(u64, u64),
     #[error("unable to set timestamp on {:?}", _0)]
     FileTime(Arc<Path>, #[source] io::Error),
     #[error("content length is an invalid range")]
@@ -131,12 +181,16 @@ pub enum Error {
     Nameless,
     #[error("network connection was interrupted while fetching")]
     NetworkChanged,
+    #[error("fetch was paused")]
+    Paused,
     #[error("unable to open fetched part")]
     OpenPart(Arc<Path>, #[source] io::Error),
     #[error("destination lacks parent")]
     Parentless,
     #[error("connection timed out")]
     TimedOut,
+    #[error("response exceeds the maximum size of {} bytes", _0)]
+    TooLarge(u64),
     #[error("error writing to file")]
     Write(#[source] io::Error),
     #[error("network input error")]
@@ -145,10 +199,14 @@ pub enum Error {
     Rename(#[source] io::Error),
     #[error("server responded with an error: {}", _0)]
     Status(StatusCode),
+    #[error("unable to sync fetched file to disk")]
+    Sync(#[source] io::Error),
+    #[error("{:?} decompression is not enabled", _0)]
+    UnsupportedCompression(Compression),
+    #[error("server responded with {} and asked to retry after {:?}", _0, _1)]
+    RetryAfter(StatusCode, Duration),
     #[error("internal tokio join handle error")]
     TokioSpawn(#[source] tokio::task::JoinError),
-    #[error("the request builder did not match the client used")]
-    InvalidGetRequestBuilder,
 }
 
 #[cfg(feature = "isahc")]
@@ -168,8 +226,12 @@ impl From<reqwest::Error> for Error {
 /// Events which are submitted by the fetcher.
 #[derive(Debug)]
 pub enum FetchEvent {
+    /// The destination is already the same as the remote file, and was not fetched again.
+    AlreadyFetched,
     /// States that we know the length of the file being fetched.
     ContentLength(u64),
+    /// The fetch failed, and will not be attempted again.
+    Failed(Box<str>),
     /// Notifies that the file has been fetched.
     Fetched,
     /// Notifies that a file is being fetched.
@@ -178,6 +240,32 @@ pub enum FetchEvent {
     Progress(u64),
     /// Notification that a fetch is being re-attempted.
     Retrying,
+    /// The server is overloaded, and asked to wait this long before retrying.
+    RetryAfter(Duration),
+    /// A failed attempt will be retried after a delay, with the reason it failed and the
+    /// number of consecutive attempts which have failed.
+    ///
+    /// After a timeout or network change, the server is probed after each delay until
+    /// it can be reached, and the event is sent again for each probe which fails.
+    RetryScheduled(Box<str>, Duration, u16),
+    /// Reports the mirror that the file is being fetched from.
+    Mirror(Box<str>),
+    /// The fetch failed over from the first mirror to the second.
+    MirrorSwitched(Box<str>, Box<str>),
+    /// A connection started fetching a range of the file from a mirror.
+    PartStarted(Range<u64>, Box<str>),
+    /// A connection finished fetching a range of the file from a mirror.
+    ///
+    /// The range begins where the part's `PartStarted` range began, and ends earlier
+    /// if another connection took over the rest of it.
+    PartFinished(Range<u64>, Box<str>),
+    /// The fetch was paused, and its connections were closed.
+    Paused,
+    /// The fetch continues from the bytes fetched by an earlier attempt, which are
+    /// reported as progress after this event.
+    Resumed(u64),
+    /// A paused fetch is continuing, and progress is reported again from the start.
+    Unpaused,
 }
 
 /// An asynchronous file fetcher for clients fetching files.
@@ -186,12 +274,19 @@ pub enum FetchEvent {
 /// runtimes, allowing you to choose between the runtime that works best for your
 /// application. A single-threaded runtime is generally recommended for fetching files,
 /// as your network connection is unlikely to be faster than a single CPU core.
+///
+/// The `C` parameter is the `HttpBackend` which requests are sent with, which defaults
+/// to the `Client` of the enabled client features.
 #[derive(new, Setters)]
-pub struct Fetcher<Data> {
+pub struct Fetcher<
+    Data,
+    #[cfg(any(feature = "isahc", feature = "reqwest"))] C = Client,
+    #[cfg(not(any(feature = "isahc", feature = "reqwest")))] C,
+> {
     /// Creates an instance of a client. The caller can decide if the instance
     /// is shared or unique.
     #[setters(skip)]
-    client: Client,
+    client: C,
 
     /// The number of concurrent connections to sustain per file being fetched.
     /// # Note
@@ -199,24 +294,94 @@ pub struct Fetcher<Data> {
     #[new(value = "1")]
     connections_per_file: u16,
 
+    /// The maximum number of concurrent connections to each host, across every source.
+    ///
+    /// Applies to every request, including those which probe a file before fetching it.
+    /// # Note
+    /// Defaults to no limit.
+    #[new(default)]
+    #[setters(strip_option)]
+    max_connections_per_host: Option<usize>,
+
+    /// Counts the connections open to each host.
+    #[new(default)]
+    #[setters(skip)]
+    host_connections: HostConnections,
+
+    /// Hosts which rejected `HEAD` requests, whose files are probed with `GET` requests.
+    #[new(default)]
+    #[setters(skip)]
+    head_rejected: Mutex<HashSet<Box<str>>>,
+
     /// Configure the delay between file requests.
     /// # Note
     /// Defaults to no delay
     #[new(value = "0")]
     delay_between_requests: u64,
 
+    /// The number of sources that `stream_from` takes from its input stream ahead of
+    /// those being fetched, which wait to be fetched in order of their priority.
+    /// # Note
+    /// Defaults to 64 sources.
+    #[new(value = "64")]
+    max_queued: usize,
+
     /// The number of attempts to make when a request fails.
+    ///
+    /// Errors which the retry policy retries without limit are not counted.
     /// # Note
     /// Defaults to 3 retries.
     #[new(value = "3")]
     retries: u16,
 
+    /// Controls the delays between attempts, and which errors are retried.
+    #[new(default)]
+    retry_policy: RetryPolicy,
+
+    /// Tracks the performance of mirrors, which may be shared with other fetchers.
+    #[new(default)]
+    mirror_health: Arc<MirrorHealth>,
+
+    /// Limits the rate at which every connection of the fetcher reads, combined.
+    ///
+    /// The limiter may be shared with other fetchers, and its limit may be changed at any time.
+    /// # Note
+    /// Defaults to no limit.
+    #[new(default)]
+    rate_limiter: Arc<RateLimiter>,
+
     /// The maximum size of a part file when downloading in parts.
     /// # Note
     /// Defaults to 2 MiB.
     #[new(value = "2 * 1024 * 1024")]
     max_part_size: u32,
 
+    /// Write parts directly into the destination, rather than into part files which
+    /// are concatenated once every part has been fetched.
+    ///
+    /// The destination is preallocated to the length of the file, and each connection
+    /// writes at the offset of the range it is fetching. This avoids copying every
+    /// part, and the space that part files would take in addition to the destination.
+    /// # Note
+    /// Defaults to false.
+    #[new(default)]
+    positional_writes: bool,
+
+    /// Reserves space on the disk for the whole file before it is fetched.
+    ///
+    /// This avoids fragmenting the file, and running out of space in the middle of a
+    /// fetch. Only takes effect on file systems which support it.
+    /// # Note
+    /// Defaults to false.
+    #[new(default)]
+    preallocate: bool,
+
+    /// How much is synced to disk before a fetched file replaces its destination.
+    /// # Note
+    /// Defaults to `Durability::Full`.
+    #[new(default)]
+    durability: Durability,
+
     /// Time in ms between progress messages
     /// # Note
     /// Defaults to 500.
@@ -234,27 +399,18 @@ pub struct Fetcher<Data> {
     #[setters(strip_option)]
     events: Option<Arc<EventSender<Arc<Data>>>>,
 
+    /// Pauses every fetch of the fetcher.
+    #[new(default)]
+    #[setters(skip)]
+    paused: Pause,
+
     /// Utilized to know when to shut down the fetching process.
     #[new(value = "Shutdown::new()")]
     shutdown: Shutdown,
 }
 
-/// The underlying Client used for the Fetcher
-pub enum Client {
-    #[cfg(feature = "isahc")]
-    Isahc(IsahcClient),
-    #[cfg(feature = "reqwest")]
-    Reqwest(ReqwestClient),
-}
-
-pub(crate) enum RequestBuilder {
-    #[cfg(feature = "isahc")]
-    Http(HttpBuilder),
-    #[cfg(feature = "reqwest")]
-    Reqwest(ReqwestBuilder),
-}
-
-impl<Data> Default for Fetcher<Data> {
+#[cfg(any(feature = "isahc", feature = "reqwest"))]
+impl<Data> Default for Fetcher<Data, Client> {
     fn default() -> Self {
         #[cfg(feature = "isahc")]
         {
@@ -274,7 +430,7 @@ impl<Data> Default for Fetcher<Data> {
             Self::new(Client::Isahc(client))
         }
 
-        #[cfg(feature = "reqwest")]
+        #[cfg(all(feature = "reqwest", not(feature = "isahc")))]
         {
             let client = ReqwestClient::builder()
                 // Keep a TCP connection alive for up to 90s
@@ -293,16 +449,53 @@ impl<Data> Default for Fetcher<Data> {
     }
 }
 
-impl<Data: Send + Sync + 'static> Fetcher<Data> {
+impl<Data: Send + Sync + 'static, C: HttpBackend> Fetcher<Data, C> {
     /// Finalizes the fetcher to prepare it for fetch tasks.
     pub fn build(self) -> Arc<Self> {
         Arc::new(self)
     }
 
+    /// The limit on the rate at which files are fetched, which may be changed at any time.
+    pub fn rate_limit(&self) -> &Arc<RateLimiter> {
+        &self.rate_limiter
+    }
+
+    /// Pauses every fetch, keeping the data fetched so far.
+    ///
+    /// Sources which are waiting to be fetched are started, but wait for the fetcher
+    /// to be resumed before sending any requests.
+    pub fn pause(&self) {
+        self.paused.set(true);
+    }
+
+    /// Continues every fetch which was paused by `pause`.
+    ///
+    /// Sources which were paused through their own handles remain paused.
+    pub fn resume(&self) {
+        self.paused.set(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_paused()
+    }
+
+    /// The performance of the mirrors that files have been fetched from.
+    pub fn mirrors(&self) -> &Arc<MirrorHealth> {
+        &self.mirror_health
+    }
+
     /// Given an input stream of source fetches, returns an output stream of fetch results.
     ///
     /// Spawns up to `concurrent` + `1` number of concurrent async tasks on the runtime.
     /// One task for managing the fetch tasks, and one task per fetch request.
+    ///
+    /// Sources are taken from the input stream as soon as they are available, and wait
+    /// for a free slot in order of their `priority`. A source with a higher priority is
+    /// fetched before every waiting source of a lower priority, even if it arrived later.
+    ///
+    /// Up to `max_queued` sources wait at a time. The input stream is not polled while
+    /// that many are waiting, so a source which arrives later than that is only ordered
+    /// among the sources which are waiting when it is taken from the stream.
     pub fn stream_from(
         self: Arc<Self>,
         inputs: impl Stream<Item = (Source, Arc<Data>)> + Send + 'static,
@@ -311,8 +504,13 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
         let shutdown = self.shutdown.clone();
         let cancel_trigger = shutdown.wait_shutdown_triggered();
         // Takes input requests and converts them into a stream of fetch requests.
-        let stream = inputs
-            .map(move |(Source { dest, urls, part }, extra)| {
+        let priority = |(source, _): &(Source, Arc<Data>)| source.priority;
+        let stream = prioritized(
+            inputs,
+            concurrent,
+            self.max_queued,
+            priority,
+            move |(source, extra)| {
                 let fetcher = self.clone();
                 async move {
                     if fetcher.delay_between_requests != 0 {
@@ -321,215 +519,256 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
                     }
 
                     tokio::spawn(async move {
+                        let (source, options) = source.into_parts();
+                        let Source {
+                            dest,
+                            urls,
+                            part,
+                            handle,
+                            ..
+                        } = source;
+
+                        if handle.is_canceled() {
+                            return (dest, extra, Err(Error::Canceled));
+                        }
+
+                        // The file which holds the data fetched so far.
+                        let partial = part.unwrap_or_else(|| staging_path(&dest));
+
                         let _token = match fetcher.shutdown.delay_shutdown_token() {
                             Ok(token) => token,
                             Err(_) => return (dest, extra, Err(Error::Canceled)),
                         };
 
-                        let task = async {
-                            match part {
-                                Some(part) => {
-                                    match fetcher.request(urls, part.clone(), extra.clone()).await {
-                                        Ok(()) => {
-                                            fs::rename(&*part, &*dest).await.map_err(Error::Rename)
-                                        }
-                                        Err(why) => Err(why),
-                                    }
-                                }
-                                None => fetcher.request(urls, dest.clone(), extra.clone()).await,
+                        let task = fetcher.clone().fetch(
+                            urls,
+                            dest.clone(),
+                            partial.clone(),
+                            options,
+                            extra.clone(),
+                        );
+
+                        let result = match handle.until_canceled(task).await {
+                            Some(result) => result,
+                            None => {
+                                info!("canceled fetch of {}", dest.display());
+                                let reason = Error::Canceled.to_string().into();
+                                let event = FetchEvent::Failed(reason);
+                                fetcher.send(|| (dest.clone(), extra.clone(), event));
+
+                                let _ = fs::remove_file(&*partial).await;
+                                discard_parts(&partial).await;
+                                Err(Error::Canceled)
                             }
                         };
 
-                        let result = task.await;
-
                         (dest, extra, result)
                     })
                     .await
                     .unwrap()
                 }
-            })
-            .buffer_unordered(concurrent)
-            .take_until(cancel_trigger);
+            },
+        );
 
-        Box::pin(stream)
+        Box::pin(stream.take_until(cancel_trigger))
     }
 
     /// Request a file from one or more URIs.
     ///
     /// At least one URI must be provided as a source for the file. Each additional URI
-    /// serves as a mirror for failover and load-balancing purposes.
+    /// serves as a mirror for failover and load-balancing purposes. Mirrors are tried
+    /// from the healthiest to the least healthy, according to the `MirrorHealth`. If a
+    /// mirror fails to serve the file, the fetch fails over to the next mirror. The
+    /// retry policy only takes effect once every mirror has failed.
+    ///
+    /// The file is fetched into a `.fetching` file next to the destination, which replaces
+    /// the destination once it has been fetched, according to the fetcher's `durability`.
+    ///
+    /// The parts of a multi-connection fetch are recorded in a `.fetch-state` file next
+    /// to the destination. If the fetch fails or the process is interrupted, a later
+    /// request for the same file resumes from the parts already on disk, provided that
+    /// the length and modification time of the file have not changed.
+    ///
+    /// Returns a report of how the file was fetched.
     pub async fn request(
         self: Arc<Self>,
         uris: Arc<[Box<str>]>,
         to: Arc<Path>,
         extra: Arc<Data>,
-    ) -> Result<(), Error> {
-        self.send(|| (to.clone(), extra.clone(), FetchEvent::Fetching));
+    ) -> Result<FetchReport, Error> {
+        let staging = staging_path(&to);
+        self.fetch(uris, to, staging, FetchOptions::default(), extra)
+            .await
+    }
+
+    /// Requests a file, validating it against a checksum which is computed as it is fetched.
+    ///
+    /// If the file does not match the checksum, or can not be decompressed, it is fetched
+    /// again from one mirror at a time. A mirror which serves a corrupted file is not used
+    /// again for the file, and the fetch fails once every mirror has served a bad file.
+    /// A destination or staging file which is already on disk is only kept if it
+    /// matches the checksum.
+    async fn fetch(
+        self: Arc<Self>,
+        uris: Arc<[Box<str>]>,
+        dest: Arc<Path>,
+        to: Arc<Path>,
+        options: FetchOptions,
+        extra: Arc<Data>,
+    ) -> Result<FetchReport, Error> {
+        self.send(|| (dest.clone(), extra.clone(), FetchEvent::Fetching));
 
-        remove_parts(&to).await;
+        let started = Instant::now();
+        let report = options.report.clone();
+        let uris = self.mirror_health.rank(&uris);
+        let attempts = Arc::new(Attempts::default());
 
-        let attempts = Arc::new(AtomicU16::new(0));
+        let task = async {
+            let mut failover = Failover::new(uris);
+
+            // Whether the partial file holds the data fetched before the fetch was paused.
+            let mut paused = false;
 
-        let fetch = || async {
             loop {
+                self.wait_while_paused(options.handle.as_ref(), &dest, &extra)
+                    .await;
+
+                if let Some(event) = failover.announce() {
+                    self.send(|| (dest.clone(), extra.clone(), event));
+                }
+
                 let task = self.clone().inner_request(
-                    &self.client,
-                    uris.clone(),
+                    failover.mirrors(),
+                    dest.clone(),
                     to.clone(),
+                    options.clone(),
                     extra.clone(),
                     attempts.clone(),
+                    paused,
                 );
 
-                let result = task.await;
-
-                if let Err(Error::NetworkChanged) | Err(Error::TimedOut) = result {
-                    let mut attempts = 5;
-                    while attempts != 0 {
-                        tokio::time::sleep(Duration::from_secs(3)).await;
-
-                        match &self.client {
-                            #[cfg(feature = "isahc")]
-                            Client::Isahc(client) => {
-                                let future = head_isahc(client, &uris[0]);
-                                let net_check =
-                                    crate::utils::timed_interrupt(Duration::from_secs(3), future);
-
-                                if net_check.await.is_ok() {
-                                    tokio::time::sleep(Duration::from_secs(3)).await;
-                                    break;
-                                }
-                            }
-                            #[cfg(feature = "reqwest")]
-                            Client::Reqwest(client) => {
-                                let future = head_reqwest(client, &uris[0]);
-                                let net_check =
-                                    crate::utils::timed_interrupt(Duration::from_secs(3), future);
-
-                                if net_check.await.is_ok() {
-                                    tokio::time::sleep(Duration::from_secs(3)).await;
-                                    break;
-                                }
-                            }
-                        };
-
-                        attempts -= 1;
-                    }
-
-                    self.send(|| (to.clone(), extra.clone(), FetchEvent::Retrying));
-                    remove_parts(&to).await;
-                    tokio::time::sleep(Duration::from_secs(3)).await;
-
-                    continue;
-                }
-
-                return result;
-            }
-        };
-
-        let task = async {
-            let mut attempted = false;
-            loop {
-                if attempted {
-                    self.send(|| (to.clone(), extra.clone(), FetchEvent::Retrying));
-                }
-
-                attempted = true;
-                remove_parts(&to).await;
-
-                let error = match fetch().await {
-                    Ok(()) => return Ok(()),
+                let error = match task.await {
+                    Ok(()) => return Ok::<(), Error>(()),
                     Err(error) => error,
                 };
 
-                if let Error::Canceled = error {
-                    return Err(error);
+                // The fetch continues from the data fetched so far once it is resumed.
+                if let Error::Paused = error {
+                    paused = true;
+                    continue;
                 }
 
-                tokio::time::sleep(Duration::from_secs(3)).await;
-
-                // Uncondtionally retry connection errors.
-                #[cfg(feature = "isahc")]
-                if let Error::IsahcClient(ref error) = error {
-                    use std::error::Error;
-                    if let Some(source) = error.source() {
-                        if let Some(error) = source.downcast_ref::<isahc::Error>() {
-                            if error.is_network() {
-                                error!("retrying due to connection error: {}", error);
-                                continue;
-                            }
-                        }
+                let error = error.with_context(|| ErrorContext {
+                    url: failover.uri().into(),
+                    dest: dest.clone(),
+                    offset: None,
+                    attempt: attempts.failed().saturating_add(1),
+                });
+
+                // A corrupted file is fetched again from the start.
+                if let Error::Checksum(_) | Error::Decompress(_) = error.without_context() {
+                    error!(
+                        "removing {} after error: {}",
+                        to.display(),
+                        context::describe(&error)
+                    );
+                    let _ = fs::remove_file(&*to).await;
+                    discard_parts(&to).await;
+
+                    // The data on disk that the attempt continued from may have been
+                    // corrupted, so the mirror is not blamed until it serves the whole file.
+                    if report.is_resumed() {
+                        paused = false;
+                        report.retried();
+                        self.send(|| (dest.clone(), extra.clone(), FetchEvent::Retrying));
+                        continue;
                     }
                 }
 
-                #[cfg(feature = "reqwest")]
-                if let Error::ReqwestClient(ref error) = error {
-                    use std::error::Error;
-                    if let Some(source) = error.source() {
-                        if let Some(error) = source.downcast_ref::<reqwest::Error>() {
-                            if error.is_connect() || error.is_request() {
-                                error!("retrying due to connection error: {}", error);
-                                continue;
-                            }
-                        }
-                    }
-                }
+                self.recover(&mut failover, error, &attempts, &dest, &extra)
+                    .await?;
 
-                error!("retrying after error encountered: {}", error);
+                report.retried();
 
-                if attempts.fetch_add(1, Ordering::SeqCst) > self.retries {
-                    return Err(error);
-                }
+                self.send(|| (dest.clone(), extra.clone(), FetchEvent::Retrying));
             }
         };
 
-        let result = task.await;
+        let result = async {
+            task.await?;
+            discard_parts(&to).await;
+
+            if to.exists() {
+                finalize(to.clone(), dest.clone(), self.durability).await?;
+            }
 
-        remove_parts(&to).await;
+            Ok::<(), Error>(())
+        };
 
-        match result {
+        match result.await {
             Ok(()) => {
-                self.send(|| (to.clone(), extra.clone(), FetchEvent::Fetched));
-
-                Ok(())
+                self.send(|| (dest.clone(), extra.clone(), FetchEvent::Fetched));
+                Ok(report.report(started.elapsed()))
+            }
+            Err(why) => {
+                let reason = context::describe(&why).into();
+                self.send(|| (dest.clone(), extra.clone(), FetchEvent::Failed(reason)));
+                Err(why)
             }
-            Err(why) => Err(why),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn inner_request(
         self: Arc<Self>,
-        client: &Client,
         uris: Arc<[Box<str>]>,
+        dest: Arc<Path>,
         to: Arc<Path>,
+        options: FetchOptions,
         extra: Arc<Data>,
-        attempts: Arc<AtomicU16>,
+        attempts: Arc<Attempts>,
+        paused: bool,
     ) -> Result<(), Error> {
         let mut length = None;
         let mut modified = None;
         let mut resume = 0;
 
-        match client {
-            #[cfg(feature = "isahc")]
-            Client::Isahc(client) => {
-                let head_response = head_isahc(client, &*uris[0]).await?;
+        options.report.attempt();
 
-                if let Some(response) = head_response.as_ref() {
-                    length = response.content_length();
-                    modified = response.last_modified();
-                }
-            }
-            #[cfg(feature = "reqwest")]
-            Client::Reqwest(client) => {
-                let head_response = head_reqwest(client, &*uris[0]).await?;
+        // Whether ranges are honored, and a response which is already sending the file.
+        let mut ranges = None;
+        let mut probed = None;
 
-                if let Some(response) = head_response.as_ref() {
-                    length = response.content_length();
-                    modified = response.last_modified();
-                }
+        if let Some(probe) = self.probe(&uris[0]).await? {
+            length = probe.length;
+            modified = probe.modified;
+            ranges = probe.ranges;
+            probed = probe.response;
+
+            let effective = probe.effective.as_ref();
+            options.report.response(&uris[0], &probe.headers, effective);
+        }
+
+        // A decompressed file is compared by its modification time alone, as its length
+        // is not the length of the remote file.
+        let compared_length = match options.decompress {
+            Some(_) => Some(None),
+            None => length.map(Some),
+        };
+
+        // The destination is not replaced if it is the same as the remote file.
+        if let (Some(length), Some(modified)) = (compared_length, modified) {
+            if is_current(&dest, length, modified) && matches_checksum(&dest, &options).await {
+                info!("already fetched {}", dest.display());
+                self.send(|| (dest.clone(), extra.clone(), FetchEvent::AlreadyFetched));
+                options.report.up_to_date();
+                let _ = fs::remove_file(&*to).await;
+                discard_parts(&to).await;
+                return Ok(());
             }
         }
 
-        // If the file already exists, validate that it is the same.
+        // If the partial file already exists, validate that it is the same.
         if to.exists() {
             if let (Some(length), Some(last_modified)) = (length, modified) {
                 match fs::metadata(to.as_ref()).await {
@@ -541,11 +780,21 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
 
                         if metadata.len() == length {
                             if ts.as_secs() == date_as_timestamp(last_modified) {
-                                info!("already fetched {}", to.display());
-                                return Ok(());
+                                if matches_checksum(&to, &options).await {
+                                    info!("already fetched {}", to.display());
+                                    return Ok(());
+                                }
+
+                                fs::remove_file(to.as_ref())
+                                    .await
+                                    .map_err(Error::MetadataRemove)?;
+                                discard_parts(&to).await;
+                            } else if ControlFile::new(&to).exists() {
+                                // The file was preallocated by a fetch which was interrupted.
+                                info!("found interrupted fetch of {}", to.display());
                             } else {
                                 error!("removing file with outdated timestamp: {:?}", to);
-                                let _ = fs::remove_file(to.as_ref())
+                                fs::remove_file(to.as_ref())
                                     .await
                                     .map_err(Error::MetadataRemove)?;
                             }
@@ -560,21 +809,55 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
                             .map_err(Error::MetadataRemove)?;
                     }
                 }
+            } else if paused {
+                // The data fetched before a pause is kept, even if it can not be validated
+                // against the modification time of the remote file.
+                if let Ok(metadata) = fs::metadata(to.as_ref()).await {
+                    if length.map_or(true, |length| metadata.len() < length) {
+                        resume = metadata.len();
+                    }
+                }
             }
         }
 
+        // A decompressed file is fetched from the start of the stream, as is a file from
+        // a server which does not honor ranges.
+        if options.decompress.is_some() || ranges == Some(false) {
+            resume = 0;
+        }
+
         // If set, this will use multiple connections to download a file in parts.
-        if self.connections_per_file > 1 {
+        if self.connections_per_file > 1 && options.decompress.is_none() {
             if let Some(length) = length {
-                if supports_range(client, &*uris[0], resume, Some(length)).await? {
-                    self.send(|| (to.clone(), extra.clone(), FetchEvent::ContentLength(length)));
-
-                    if resume != 0 {
-                        self.send(|| (to.clone(), extra.clone(), FetchEvent::Progress(resume)));
-                    }
+                let supported = match ranges {
+                    Some(supported) => supported,
+                    None => self.supports_range(&uris[0], resume, Some(length)).await?,
+                };
 
-                    let result = get_many(
+                if supported {
+                    self.send(|| {
+                        (
+                            dest.clone(),
+                            extra.clone(),
+                            FetchEvent::ContentLength(length),
+                        )
+                    });
+
+                    // Part files take space until they are concatenated into the file.
+                    let remaining = length.saturating_sub(resume);
+                    let parts = if self.positional_writes {
+                        0
+                    } else {
+                        let in_flight =
+                            u64::from(self.connections_per_file) * u64::from(self.max_part_size);
+                        remaining.min(in_flight)
+                    };
+
+                    ensure_space(&to, remaining + parts)?;
+
+                    get_many(
                         self.clone(),
+                        dest,
                         to.clone(),
                         uris,
                         resume,
@@ -582,12 +865,9 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
                         modified,
                         extra,
                         attempts.clone(),
+                        options,
                     )
-                    .await;
-
-                    if let Err(why) = result {
-                        return Err(why);
-                    }
+                    .await?;
 
                     if let Some(modified) = modified {
                         update_modified(&to, modified)?;
@@ -598,72 +878,85 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
             }
         }
 
+        // Parts of an earlier multi-connection fetch cannot be used by a single connection.
+        discard_parts(&to).await;
+
         if let Some(length) = length {
-            self.send(|| (to.clone(), extra.clone(), FetchEvent::ContentLength(length)));
+            self.send(|| {
+                (
+                    dest.clone(),
+                    extra.clone(),
+                    FetchEvent::ContentLength(length),
+                )
+            });
 
             if resume > length {
                 resume = 0;
             }
         }
 
-        let mut request = match client {
-            #[cfg(feature = "isahc")]
-            Client::Isahc(_) => RequestBuilder::Http(HttpRequest::get(&*uris[0])),
-            #[cfg(feature = "reqwest")]
-            Client::Reqwest(client) => RequestBuilder::Reqwest(client.get(&*uris[0])),
-        };
+        let mut request = HttpRequest::get(&*uris[0]);
 
         if resume != 0 {
-            if let Ok(true) = supports_range(client, &*uris[0], resume, length).await {
-                match request {
-                    #[cfg(feature = "isahc")]
-                    RequestBuilder::Http(inner) => {
-                        request = RequestBuilder::Http(
-                            inner.header("Range", range::to_string(resume, length)),
-                        );
-                    }
-                    #[cfg(feature = "reqwest")]
-                    RequestBuilder::Reqwest(inner) => {
-                        request = RequestBuilder::Reqwest(
-                            inner.header("Range", range::to_string(resume, length)),
-                        );
-                    }
-                }
-                self.send(|| (to.clone(), extra.clone(), FetchEvent::Progress(resume)));
+            let supported = match ranges {
+                Some(supported) => Ok(supported),
+                None => self.supports_range(&uris[0], resume, length).await,
+            };
+
+            if let Ok(true) = supported {
+                request = request.header("Range", range::to_string(resume, length));
+                self.send(|| (dest.clone(), extra.clone(), FetchEvent::Resumed(resume)));
+                options.report.resumed(resume);
+                self.send(|| (dest.clone(), extra.clone(), FetchEvent::Progress(resume)));
             } else {
                 resume = 0;
             }
         }
 
+        if let Some(length) = length {
+            ensure_space(&to, length - resume)?;
+        }
+
+        let location = FetchLocation::create(to.clone(), resume != 0).await?;
+
+        if let (true, Some(length)) = (self.preallocate, length) {
+            preallocate(&location.file, length, &to)?;
+        }
+
+        // The file continues from the probe's response if it is already sending the file.
+        let request = match probed {
+            Some(response) => GetRequest::Received(uris[0].clone(), response),
+            None => request.into(),
+        };
+
         let path = match crate::get(
             self.clone(),
             request,
-            FetchLocation::create(to.clone(), resume != 0).await?,
-            to.clone(),
+            location,
+            dest.clone(),
             extra.clone(),
             attempts.clone(),
+            None,
+            options.clone(),
         )
         .await
         {
             Ok((path, _)) => path,
-            Err(Error::Status(StatusCode::NOT_MODIFIED)) => to,
+            Err(why) if is_status(&why, StatusCode::NOT_MODIFIED) => to,
 
             // Server does not support if-modified-since
-            Err(Error::Status(StatusCode::NOT_IMPLEMENTED)) => {
-                let request = match client {
-                    #[cfg(feature = "isahc")]
-                    Client::Isahc(_) => RequestBuilder::Http(HttpRequest::get(&*uris[0])),
-                    #[cfg(feature = "reqwest")]
-                    Client::Reqwest(client) => RequestBuilder::Reqwest(client.get(&*uris[0])),
-                };
+            Err(why) if is_status(&why, StatusCode::NOT_IMPLEMENTED) => {
+                let request = HttpRequest::get(&*uris[0]);
 
                 let (path, _) = crate::get(
                     self.clone(),
                     request,
                     FetchLocation::create(to.clone(), resume != 0).await?,
-                    to.clone(),
+                    dest,
                     extra.clone(),
                     attempts,
+                    None,
+                    options,
                 )
                 .await?;
 
@@ -680,114 +973,251 @@ impl<Data: Send + Sync + 'static> Fetcher<Data> {
         Ok(())
     }
 
-    fn send(&self, event: impl FnOnce() -> (Arc<Path>, Arc<Data>, FetchEvent)) {
-        if let Some(sender) = self.events.as_ref() {
-            let _ = sender.send(event());
+    /// Waits for the server to become reachable after a timeout or network change,
+    /// calling `scheduled` with each delay before the server is probed.
+    async fn wait_for_connectivity(&self, uri: &str, attempt: u16, scheduled: impl Fn(Duration)) {
+        let policy = &self.retry_policy;
+
+        for probe in 0..policy.probe_count() {
+            let delay = policy.delay(u32::from(attempt) + u32::from(probe));
+            scheduled(delay);
+            tokio::time::sleep(delay).await;
+
+            let future = self.probe(uri);
+            let net_check = crate::utils::timed_interrupt(Duration::from_secs(3), future);
+
+            if net_check.await.is_ok() {
+                return;
+            }
         }
     }
-}
 
-#[cfg(feature = "isahc")]
-async fn head_isahc(
-    client: &IsahcClient,
-    uri: &str,
-) -> Result<Option<IsahcResponse<AsyncBody>>, Error> {
-    let request = HttpRequest::head(uri).body(()).unwrap();
-
-    match validate_isahc(client.send_async(request).await?).map(Some) {
-        result @ Ok(_) => result,
-        Err(Error::Status(StatusCode::NOT_MODIFIED))
-        | Err(Error::Status(StatusCode::NOT_IMPLEMENTED)) => Ok(None),
-        Err(other) => Err(other),
-    }
-}
+    /// Waits before the next attempt after `error`, or returns `false` if the fetch
+    /// should not be retried.
+    async fn backoff(
+        &self,
+        error: &Error,
+        uri: &str,
+        attempts: &Attempts,
+        to: &Arc<Path>,
+        extra: &Arc<Data>,
+    ) -> bool {
+        let policy = &self.retry_policy;
+        let decision = policy.decision(error);
+
+        if decision == RetryDecision::Abort {
+            return false;
+        }
 
-#[cfg(feature = "reqwest")]
-async fn head_reqwest(client: &ReqwestClient, uri: &str) -> Result<Option<ReqwestResponse>, Error> {
-    let request = client.head(uri).build().unwrap();
+        // Waiting for an overloaded server does not count as an attempt, but it does
+        // count towards the time spent retrying.
+        if let Error::RetryAfter(status, delay) = *error.without_context() {
+            if attempts.wait() > policy.max_retry_after_wait_count() {
+                return false;
+            }
+
+            if let Some(max_elapsed) = policy.max_elapsed_time() {
+                if attempts.failing_since().elapsed() >= max_elapsed {
+                    return false;
+                }
+            }
+
+            let delay = delay.min(policy.max_retry_after_delay());
+            info!(
+                "server responded with {}, retrying after {:?}",
+                status, delay
+            );
+
+            let attempt = attempts.failed();
+            let reason = context::describe(error).into();
+            self.send(|| (to.clone(), extra.clone(), FetchEvent::RetryAfter(delay)));
+            self.send(|| {
+                let event = FetchEvent::RetryScheduled(reason, delay, attempt);
+                (to.clone(), extra.clone(), event)
+            });
 
-    match validate_reqwest(client.execute(request).await?).map(Some) {
-        result @ Ok(_) => result,
-        Err(Error::Status(StatusCode::NOT_MODIFIED))
-        | Err(Error::Status(StatusCode::NOT_IMPLEMENTED)) => Ok(None),
-        Err(other) => Err(other),
+            tokio::time::sleep(delay).await;
+
+            return true;
+        }
+
+        // The attempt counter is reset whenever the fetch makes progress.
+        let attempt = attempts.fail();
+
+        if decision == RetryDecision::Retry && attempt > self.retries {
+            return false;
+        }
+
+        if let Some(max_elapsed) = policy.max_elapsed_time() {
+            if attempts.failing_since().elapsed() >= max_elapsed {
+                return false;
+            }
+        }
+
+        let reason: Box<str> = context::describe(error).into();
+        error!("retrying after error encountered: {}", reason);
+        let scheduled = |delay| {
+            self.send(|| {
+                let event = FetchEvent::RetryScheduled(reason.clone(), delay, attempt);
+                (to.clone(), extra.clone(), event)
+            });
+        };
+
+        match ErrorClass::of(error) {
+            ErrorClass::Timeout | ErrorClass::NetworkChanged => {
+                self.wait_for_connectivity(uri, attempt, scheduled).await
+            }
+            _ => {
+                let delay = policy.delay(attempt.into());
+                scheduled(delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        true
     }
-}
 
-async fn supports_range(
-    client: &Client,
-    uri: &str,
-    resume: u64,
-    length: Option<u64>,
-) -> Result<bool, Error> {
-    match client {
-        #[cfg(feature = "isahc")]
-        Client::Isahc(client) => {
-            let request = HttpRequest::head(uri)
-                .header("Range", range::to_string(resume, length).as_str())
-                .body(())
-                .unwrap();
-
-            let response = client.send_async(request).await?;
-
-            if response.status() == StatusCode::PARTIAL_CONTENT {
-                if let Some(header) = response.headers().get("Content-Range") {
-                    if let Ok(header) = header.to_str() {
-                        if header.starts_with(&format!("bytes {}-", resume)) {
-                            return Ok(true);
-                        }
-                    }
-                }
+    /// Waits until neither the fetcher nor the fetch's handle are paused.
+    async fn wait_while_paused(
+        &self,
+        handle: Option<&FetchHandle>,
+        to: &Arc<Path>,
+        extra: &Arc<Data>,
+    ) {
+        let paused = || self.is_paused() || handle.is_some_and(FetchHandle::is_paused);
+
+        if !paused() {
+            return;
+        }
 
-                Ok(false)
-            } else {
-                validate_isahc(response).map(|_| false)
+        info!("paused fetch of {}", to.display());
+        self.send(|| (to.clone(), extra.clone(), FetchEvent::Paused));
+
+        loop {
+            let fetcher_changed = self.paused.changed();
+            let handle_changed = handle.map(|handle| handle.pause_state().changed());
+
+            if !paused() {
+                break;
             }
+
+            let handle_changed = async {
+                match handle_changed {
+                    Some(changed) => changed.await,
+                    None => future::pending().await,
+                }
+            };
+
+            futures::pin_mut!(fetcher_changed);
+            futures::pin_mut!(handle_changed);
+
+            future::select(fetcher_changed, handle_changed).await;
         }
-        #[cfg(feature = "reqwest")]
-        Client::Reqwest(client) => {
-            let request = client
-                .head(uri)
-                .header("Range", range::to_string(resume, length).as_str())
-                .build()
-                .unwrap();
 
-            let response = client.execute(request).await?;
+        info!("resumed fetch of {}", to.display());
+        self.send(|| (to.clone(), extra.clone(), FetchEvent::Unpaused));
+    }
 
-            if response.status() == StatusCode::PARTIAL_CONTENT {
-                if let Some(header) = response.headers().get("Content-Range") {
-                    if let Ok(header) = header.to_str() {
-                        if header.starts_with(&format!("bytes {}-", resume)) {
-                            return Ok(true);
-                        }
+    async fn supports_range(
+        &self,
+        uri: &str,
+        resume: u64,
+        length: Option<u64>,
+    ) -> Result<bool, Error> {
+        let request = HttpRequest::head(uri)
+            .header("Range", range::to_string(resume, length).as_str())
+            .body(())
+            .unwrap();
+
+        let response = self.dispatch(request, None).await?;
+
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            if let Some(header) = response.headers().get("Content-Range") {
+                if let Ok(header) = header.to_str() {
+                    if header.starts_with(&format!("bytes {}-", resume)) {
+                        return Ok(true);
                     }
                 }
-
-                Ok(false)
-            } else {
-                validate_reqwest(response).map(|_| false)
             }
+
+            Ok(false)
+        } else {
+            validate(response).map(|_| false)
         }
     }
-}
 
-#[cfg(feature = "isahc")]
-fn validate_isahc(response: IsahcResponse<AsyncBody>) -> Result<IsahcResponse<AsyncBody>, Error> {
-    let status = response.status();
+    /// Sends a request to the backend, recording the health of the mirror.
+    ///
+    /// Waits for a connection to the host to become available first, which does not
+    /// count towards the `timeout`. The connection is held until the body is dropped.
+    async fn dispatch(
+        &self,
+        request: HttpRequest<()>,
+        timeout: Option<Duration>,
+    ) -> Result<HttpResponse<Body>, Error> {
+        let uri = request.uri().to_string();
+
+        let permit = match self.max_connections_per_host {
+            Some(limit) => Some(self.host_connections.acquire(&uri, limit).await),
+            None => None,
+        };
 
-    if status.is_informational() || status.is_success() {
-        Ok(response)
-    } else {
-        Err(Error::Status(status))
+        let started = Instant::now();
+
+        let result = match timeout {
+            Some(timeout) => {
+                let request = self.client.send_request(request);
+                crate::utils::timed_interrupt(timeout, request).await
+            }
+            None => self.client.send_request(request).await,
+        };
+
+        match result.as_ref().map(|response| response.status()) {
+            Ok(status)
+                if status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::NOT_FOUND =>
+            {
+                self.mirror_health.record_failure(&uri)
+            }
+            Ok(_) => self.mirror_health.record_latency(&uri, started.elapsed()),
+            Err(Error::NetworkChanged) => (),
+            Err(_) => self.mirror_health.record_failure(&uri),
+        }
+
+        match permit {
+            Some(permit) => result.map(|response| {
+                response.map(|body| {
+                    Box::new(PermitBody {
+                        body,
+                        _permit: permit,
+                    }) as Body
+                })
+            }),
+            None => result,
+        }
+    }
+
+    fn send(&self, event: impl FnOnce() -> (Arc<Path>, Arc<Data>, FetchEvent)) {
+        if let Some(sender) = self.events.as_ref() {
+            let _ = sender.send(event());
+        }
     }
 }
 
-#[cfg(feature = "reqwest")]
-fn validate_reqwest(response: ReqwestResponse) -> Result<ReqwestResponse, Error> {
+fn is_status(error: &Error, status: StatusCode) -> bool {
+    matches!(error.without_context(), Error::Status(code) if *code == status)
+}
+
+fn validate<T>(response: HttpResponse<T>) -> Result<HttpResponse<T>, Error> {
     let status = response.status();
 
     if status.is_informational() || status.is_success() {
         Ok(response)
+    } else if let (StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE, Some(delay)) =
+        (status, response.retry_after())
+    {
+        Err(Error::RetryAfter(status, delay))
     } else {
         Err(Error::Status(status))
     }
@@ -796,10 +1226,10 @@ fn validate_reqwest(response: ReqwestResponse) -> Result<ReqwestResponse, Error>
 trait ResponseExt {
     fn content_length(&self) -> Option<u64>;
     fn last_modified(&self) -> Option<HttpDate>;
+    fn retry_after(&self) -> Option<Duration>;
 }
 
-#[cfg(feature = "isahc")]
-impl<T> ResponseExt for IsahcResponse<T> {
+impl<T> ResponseExt for HttpResponse<T> {
     fn content_length(&self) -> Option<u64> {
         let header = self.headers().get("content-length")?;
         header.to_str().ok()?.parse::<u64>().ok()
@@ -811,20 +1241,65 @@ impl<T> ResponseExt for IsahcResponse<T> {
             .ok()
             .map(HttpDate::from)
     }
+
+    fn retry_after(&self) -> Option<Duration> {
+        let header = self.headers().get("retry-after")?.to_str().ok()?.trim();
+
+        if let Ok(seconds) = header.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+
+        let date = httpdate::parse_http_date(header).ok()?;
+        Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+    }
 }
 
-#[cfg(feature = "reqwest")]
-impl ResponseExt for ReqwestResponse {
-    fn content_length(&self) -> Option<u64> {
-        let header = self.headers().get("content-length")?;
-        header.to_str().ok()?.parse::<u64>().ok()
+/// Removes the part files and control file of a multi-connection fetch.
+async fn discard_parts(to: &Path) {
+    remove_parts(to).await;
+    ControlFile::new(to).remove();
+}
+
+/// Whether the file at `path` has the modification time of the remote file, and its
+/// length if it is given.
+fn is_current(path: &Path, length: Option<u64>, modified: HttpDate) -> bool {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+
+    let timestamp = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+
+    length.map_or(true, |length| metadata.len() == length)
+        && timestamp.is_some_and(|ts| ts.as_secs() == date_as_timestamp(modified))
+}
+
+/// Whether a file which is already on disk matches the checksum of the source, if
+/// it has one.
+async fn matches_checksum(path: &Arc<Path>, options: &FetchOptions) -> bool {
+    let checksum = match options.checksum.as_ref() {
+        Some(checksum) => checksum,
+        None => return true,
+    };
+
+    // The checksum of a compressed file can not be computed from its decompressed bytes.
+    if let Some(Decompress {
+        checksum_decompressed: false,
+        ..
+    }) = options.decompress
+    {
+        return false;
     }
 
-    fn last_modified(&self) -> Option<HttpDate> {
-        let header = self.headers().get("last-modified")?;
-        httpdate::parse_http_date(header.to_str().ok()?)
-            .ok()
-            .map(HttpDate::from)
+    match checksum.validate_file(path.clone()).await {
+        Ok(()) => true,
+        Err(why) => {
+            error!("{} does not match its checksum: {}", path.display(), why);
+            false
+        }
     }
 }
 
@@ -850,3 +1325,238 @@ async fn remove_parts(to: &Path) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use http::Method;
+    use std::time::{Duration, SystemTime};
+
+    const URI: &str = "http://mirror/file";
+
+    fn modified() -> HttpDate {
+        HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+    }
+
+    fn serve(backend: &MockBackend, body: Vec<u8>) {
+        backend.serve(URI, MockFile::new(body).last_modified(modified()));
+    }
+
+    /// Writes a file with the length and modification time of the served file, whose
+    /// contents differ from it.
+    fn corrupt(path: &Path, length: usize) {
+        let mut corrupted = contents(length);
+        corrupted[length / 2] ^= 1;
+        std::fs::write(path, corrupted).unwrap();
+        update_modified(&Arc::from(path), modified()).unwrap();
+    }
+
+    fn gets(backend: &MockBackend) -> usize {
+        let requests = backend.requests();
+        requests
+            .iter()
+            .filter(|r| r.method == http::Method::GET)
+            .count()
+    }
+
+    #[test]
+    fn destinations_matching_the_checksum_are_not_fetched_again() {
+        let backend = Arc::new(MockBackend::default());
+        serve(&backend, contents(4096));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        std::fs::write(&dest, contents(4096)).unwrap();
+        update_modified(&Arc::from(dest.as_path()), modified()).unwrap();
+
+        let fetcher = support::fetcher(&backend);
+        let report = support::fetch_checked(fetcher, &[URI], &dest, &contents(4096)).unwrap();
+
+        assert_eq!(report.outcome, FetchOutcome::UpToDate);
+        assert_eq!(gets(&backend), 0);
+    }
+
+    #[test]
+    fn corrupted_destinations_are_fetched_again() {
+        let backend = Arc::new(MockBackend::default());
+        serve(&backend, contents(4096));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        corrupt(&dest, 4096);
+
+        let fetcher = support::fetcher(&backend);
+        let report = support::fetch_checked(fetcher, &[URI], &dest, &contents(4096)).unwrap();
+
+        assert_eq!(report.outcome, FetchOutcome::Fetched);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(4096));
+        assert_eq!(gets(&backend), 1);
+    }
+
+    #[test]
+    fn corrupted_staging_files_are_fetched_again() {
+        let backend = Arc::new(MockBackend::default());
+        serve(&backend, contents(4096));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+        corrupt(&staging_path(&dest), 4096);
+
+        let fetcher = support::fetcher(&backend);
+        let report = support::fetch_checked(fetcher, &[URI], &dest, &contents(4096)).unwrap();
+
+        assert_eq!(report.outcome, FetchOutcome::Fetched);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(4096));
+        assert!(!staging_path(&dest).exists());
+    }
+
+    /// Fetches the file served from `uris`, with the events which were sent for it.
+    fn fetch_events(
+        fetcher: Fetcher<(), Arc<MockBackend>>,
+        uris: &[&str],
+        dest: &Path,
+    ) -> (Result<FetchReport, Error>, Vec<FetchEvent>) {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let result = support::fetch(fetcher.events(events_tx), uris, dest);
+
+        let mut events = Vec::new();
+        while let Ok((_, _, event)) = events_rx.try_recv() {
+            events.push(event);
+        }
+
+        (result, events)
+    }
+
+    /// The events other than progress, as they are printed.
+    fn described(events: &[FetchEvent]) -> Vec<String> {
+        events
+            .iter()
+            .filter(|event| !matches!(event, FetchEvent::Progress(_)))
+            .map(|event| format!("{:?}", event))
+            .collect()
+    }
+
+    #[test]
+    fn failing_over_reports_the_mirrors_and_the_data_resumed() {
+        let file = MockFile::new(contents(4096)).last_modified(modified());
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://a/file", file.clone());
+        backend.serve("http://b/file", file);
+
+        let disconnect = Fault::Disconnect { after: 1000 };
+        backend.fault("http://a/file", Some(Method::GET), disconnect);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let uris = ["http://a/file", "http://b/file"];
+        let (result, events) = fetch_events(support::fetcher(&backend), &uris, &dest);
+        result.unwrap();
+
+        assert_eq!(
+            described(&events),
+            [
+                "Fetching",
+                "Mirror(\"http://a/file\")",
+                "ContentLength(4096)",
+                "Retrying",
+                "MirrorSwitched(\"http://a/file\", \"http://b/file\")",
+                "ContentLength(4096)",
+                "Resumed(1000)",
+                "Fetched",
+            ]
+        );
+
+        // The data resumed is reported as progress, followed by the rest of the file.
+        let resumed = events
+            .iter()
+            .position(|event| matches!(event, FetchEvent::Resumed(_)))
+            .unwrap();
+
+        let progress: Vec<u64> = events[resumed..]
+            .iter()
+            .filter_map(|event| match event {
+                FetchEvent::Progress(bytes) => Some(*bytes),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(progress[0], 1000);
+        assert_eq!(progress.iter().sum::<u64>(), 4096);
+
+        // A later fetch finds that the destination is already the same as the remote file.
+        let (result, events) = fetch_events(support::fetcher(&backend), &uris, &dest);
+        result.unwrap();
+
+        assert_eq!(
+            described(&events),
+            [
+                "Fetching",
+                "Mirror(\"http://a/file\")",
+                "AlreadyFetched",
+                "Fetched"
+            ]
+        );
+    }
+
+    #[test]
+    fn parts_are_reported_as_they_start_and_finish() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(128 * 1024)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        // Parts this small are never split by an idle connection.
+        let fetcher = support::fetcher(&backend)
+            .connections_per_file(2)
+            .max_part_size(32 * 1024);
+
+        let (result, events) = fetch_events(fetcher, &[URI], &dest);
+        result.unwrap();
+
+        let described = described(&events);
+        assert_eq!(
+            described[..3],
+            [
+                "Fetching",
+                "Mirror(\"http://mirror/file\")",
+                "ContentLength(131072)"
+            ]
+        );
+        assert_eq!(described.last().unwrap(), "Fetched");
+
+        let mut started = Vec::new();
+        let mut finished = Vec::new();
+
+        for event in &events {
+            match event {
+                FetchEvent::PartStarted(range, uri) => {
+                    assert_eq!(&**uri, URI);
+                    started.push(range.clone());
+                }
+                FetchEvent::PartFinished(range, uri) => {
+                    assert_eq!(&**uri, URI);
+                    assert!(
+                        started.contains(range),
+                        "{:?} finished before it started",
+                        range
+                    );
+                    finished.push(range.clone());
+                }
+                _ => (),
+            }
+        }
+
+        started.sort_by_key(|range| range.start);
+        finished.sort_by_key(|range| range.start);
+
+        let expected: Vec<_> = (0..4)
+            .map(|part| part * 32768..(part + 1) * 32768)
+            .collect();
+        assert_eq!(started, expected);
+        assert_eq!(finished, expected);
+    }
+}
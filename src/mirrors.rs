@@ -0,0 +1,345 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The weight given to the newest sample in the moving averages.
+const SMOOTHING: f64 = 0.3;
+
+/// The amount of data that mirrors are compared by the time it would take to fetch.
+const REFERENCE_SIZE: f64 = 1024.0 * 1024.0;
+
+/// How long a failure counts against a mirror.
+const FAILURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// A snapshot of the health of a mirror.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MirrorStats {
+    /// Smoothed rate at which response bodies are received, in bytes per second.
+    pub throughput: Option<f64>,
+    /// Smoothed time taken to receive response headers.
+    pub latency: Option<Duration>,
+    /// Failures since the last successful transfer, within the last five minutes.
+    pub failures: u32,
+    /// Requests which are currently assigned to the mirror.
+    pub active: usize,
+}
+
+#[derive(Default)]
+struct Host {
+    stats: MirrorStats,
+    last_failure: Option<Instant>,
+}
+
+impl Host {
+    fn recent_failures(&self) -> u32 {
+        match self.last_failure {
+            Some(last) if last.elapsed() < FAILURE_WINDOW => self.stats.failures,
+            _ => 0,
+        }
+    }
+}
+
+/// Tracks the performance of each host that files are fetched from.
+///
+/// Shared by every fetch performed by a `Fetcher`, so that knowledge about a slow or
+/// failing mirror from one fetch is applied to all others. Mirrors are ordered by the
+/// estimated time to fetch a megabyte from them, which is penalized for each recent
+/// failure. Hosts without measurements are assumed to perform as well as the average
+/// of the measured hosts.
+#[derive(Default)]
+pub struct MirrorHealth {
+    hosts: Mutex<HashMap<Box<str>, Host>>,
+}
+
+impl MirrorHealth {
+    /// A snapshot of the health of the host serving `uri`.
+    pub fn stats(&self, uri: &str) -> Option<MirrorStats> {
+        let hosts = self.hosts.lock().unwrap();
+        let host = hosts.get(host_of(uri))?;
+
+        Some(MirrorStats {
+            failures: host.recent_failures(),
+            ..host.stats
+        })
+    }
+
+    /// Records how long it took for a host to respond to a request.
+    pub fn record_latency(&self, uri: &str, latency: Duration) {
+        self.update(uri, |host| {
+            host.stats.latency = Some(match host.stats.latency {
+                Some(average) => average.mul_f64(1.0 - SMOOTHING) + latency.mul_f64(SMOOTHING),
+                None => latency,
+            });
+        });
+    }
+
+    /// Records a body which was successfully received from a host.
+    pub fn record_transfer(&self, uri: &str, bytes: u64, elapsed: Duration) {
+        self.update(uri, |host| {
+            host.stats.failures = 0;
+
+            if bytes == 0 || elapsed.is_zero() {
+                return;
+            }
+
+            let rate = bytes as f64 / elapsed.as_secs_f64();
+            host.stats.throughput = Some(match host.stats.throughput {
+                Some(average) => average * (1.0 - SMOOTHING) + rate * SMOOTHING,
+                None => rate,
+            });
+        });
+    }
+
+    /// Records a failed request to a host.
+    pub fn record_failure(&self, uri: &str) {
+        self.update(uri, |host| {
+            host.stats.failures = host.recent_failures() + 1;
+            host.last_failure = Some(Instant::now());
+        });
+    }
+
+    /// Orders mirrors from the healthiest to the least healthy.
+    ///
+    /// Mirrors which are equally healthy retain their original order.
+    pub fn rank(&self, uris: &[Box<str>]) -> Arc<[Box<str>]> {
+        let hosts = self.hosts.lock().unwrap();
+        let scores = scores(&hosts, uris);
+
+        let mut order: Vec<usize> = (0..uris.len()).collect();
+        order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+
+        order.into_iter().map(|index| uris[index].clone()).collect()
+    }
+
+    /// Picks the mirror that a new request should be sent to, taking into account the
+    /// requests already assigned to each mirror.
+    ///
+    /// The mirror is considered active until the returned guard is dropped.
+    pub(crate) fn select(self: &Arc<Self>, uris: &[Box<str>]) -> (Box<str>, ActiveGuard) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let scores = scores(&hosts, uris);
+
+        let load = |index: usize| {
+            let active = hosts
+                .get(host_of(&uris[index]))
+                .map_or(0, |h| h.stats.active);
+            scores[index] / (active + 1) as f64
+        };
+
+        let mut selected = 0;
+        for index in 1..uris.len() {
+            if load(index) > load(selected) {
+                selected = index;
+            }
+        }
+
+        let uri = uris[selected].clone();
+        let host: Box<str> = host_of(&uri).into();
+        hosts.entry(host.clone()).or_default().stats.active += 1;
+
+        let guard = ActiveGuard {
+            health: self.clone(),
+            host,
+        };
+
+        (uri, guard)
+    }
+
+    fn update(&self, uri: &str, func: impl FnOnce(&mut Host)) {
+        let mut hosts = self.hosts.lock().unwrap();
+        func(hosts.entry(host_of(uri).into()).or_default());
+    }
+}
+
+/// Marks a request as active on a mirror until dropped.
+pub(crate) struct ActiveGuard {
+    health: Arc<MirrorHealth>,
+    host: Box<str>,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        if let Some(host) = self.health.hosts.lock().unwrap().get_mut(&self.host) {
+            host.stats.active = host.stats.active.saturating_sub(1);
+        }
+    }
+}
+
+/// The authority of a URI, which mirrors are tracked by.
+pub(crate) fn host_of(uri: &str) -> &str {
+    let rest = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    authority.rsplit('@').next().unwrap_or(authority)
+}
+
+/// Scores each mirror by the rate at which it is expected to serve a file.
+fn scores(hosts: &HashMap<Box<str>, Host>, uris: &[Box<str>]) -> Vec<f64> {
+    let known = |field: fn(&MirrorStats) -> Option<f64>| {
+        let samples: Vec<f64> = uris
+            .iter()
+            .filter_map(|uri| hosts.get(host_of(uri)))
+            .filter_map(|host| field(&host.stats))
+            .collect();
+
+        if samples.is_empty() {
+            None
+        } else {
+            Some(samples.iter().sum::<f64>() / samples.len() as f64)
+        }
+    };
+
+    let average_throughput = known(|stats| stats.throughput);
+    let average_latency = known(|stats| stats.latency.map(|l| l.as_secs_f64()));
+
+    uris.iter()
+        .map(|uri| {
+            let host = hosts.get(host_of(uri));
+            let stats = host.map(|host| host.stats).unwrap_or_default();
+
+            let latency = stats
+                .latency
+                .map(|l| l.as_secs_f64())
+                .or(average_latency)
+                .unwrap_or(0.0);
+
+            let transfer = stats
+                .throughput
+                .or(average_throughput)
+                .map_or(0.0, |rate| REFERENCE_SIZE / rate);
+
+            let failures = host.map_or(0, Host::recent_failures);
+            let penalty = f64::from(1 + failures).powi(2);
+
+            let expected = latency + transfer;
+            if expected > 0.0 {
+                1.0 / (expected * penalty)
+            } else {
+                1.0 / penalty
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIRRORS: [&str; 2] = ["http://a/file", "http://b/file"];
+
+    fn mirrors() -> Vec<Box<str>> {
+        MIRRORS.iter().map(|&uri| Box::from(uri)).collect()
+    }
+
+    fn ranked(health: &MirrorHealth) -> Vec<String> {
+        health
+            .rank(&mirrors())
+            .iter()
+            .map(|uri| uri.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn equally_healthy_mirrors_keep_their_order() {
+        let health = MirrorHealth::default();
+        assert_eq!(ranked(&health), MIRRORS);
+
+        health.record_transfer("http://a/file", 1 << 20, Duration::from_secs(1));
+        health.record_transfer("http://b/file", 1 << 20, Duration::from_secs(1));
+        assert_eq!(ranked(&health), MIRRORS);
+    }
+
+    #[test]
+    fn failing_mirrors_are_ranked_below_healthy_mirrors() {
+        let health = MirrorHealth::default();
+        health.record_failure("http://a/file");
+
+        assert_eq!(health.stats("http://a/file").unwrap().failures, 1);
+        assert_eq!(ranked(&health), ["http://b/file", "http://a/file"]);
+    }
+
+    #[test]
+    fn slow_mirrors_are_ranked_below_fast_mirrors() {
+        let health = MirrorHealth::default();
+        health.record_transfer("http://a/file", 1 << 20, Duration::from_secs(10));
+        health.record_transfer("http://b/file", 1 << 20, Duration::from_secs(1));
+
+        assert_eq!(ranked(&health), ["http://b/file", "http://a/file"]);
+
+        // A mirror which responds slowly is also ranked lower.
+        let health = MirrorHealth::default();
+        health.record_latency("http://a/file", Duration::from_secs(2));
+        health.record_latency("http://b/file", Duration::from_millis(20));
+
+        assert_eq!(ranked(&health), ["http://b/file", "http://a/file"]);
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_a_transfer_or_over_time() {
+        let health = MirrorHealth::default();
+        health.record_failure("http://a/file");
+        health.record_transfer("http://a/file", 1 << 20, Duration::from_secs(1));
+
+        assert_eq!(health.stats("http://a/file").unwrap().failures, 0);
+        assert_eq!(ranked(&health), MIRRORS);
+
+        let health = MirrorHealth::default();
+        health.record_failure("http://a/file");
+
+        let expired = Instant::now().checked_sub(FAILURE_WINDOW + Duration::from_secs(1));
+        health
+            .hosts
+            .lock()
+            .unwrap()
+            .get_mut("a")
+            .unwrap()
+            .last_failure = expired;
+
+        assert_eq!(health.stats("http://a/file").unwrap().failures, 0);
+        assert_eq!(ranked(&health), MIRRORS);
+    }
+
+    #[test]
+    fn slow_mirrors_recover_as_they_speed_up() {
+        let health = MirrorHealth::default();
+        health.record_transfer("http://a/file", 1 << 20, Duration::from_secs(10));
+        health.record_transfer("http://b/file", 1 << 20, Duration::from_secs(2));
+
+        let mut transfers = 0;
+        while ranked(&health)[0] != MIRRORS[0] {
+            health.record_transfer("http://a/file", 1 << 20, Duration::from_secs(1));
+            transfers += 1;
+            assert!(transfers < 10, "mirror did not recover");
+        }
+
+        // A single fast transfer does not outweigh the slow ones before it.
+        assert!(transfers > 1);
+    }
+
+    #[test]
+    fn requests_are_spread_across_equally_healthy_mirrors() {
+        let health = Arc::new(MirrorHealth::default());
+
+        let (first, first_guard) = health.select(&mirrors());
+        let (second, _second_guard) = health.select(&mirrors());
+
+        assert_eq!(&*first, "http://a/file");
+        assert_eq!(&*second, "http://b/file");
+        assert_eq!(health.stats("http://a/file").unwrap().active, 1);
+
+        drop(first_guard);
+        assert_eq!(health.stats("http://a/file").unwrap().active, 0);
+    }
+
+    #[test]
+    fn mirrors_are_tracked_by_host() {
+        assert_eq!(
+            host_of("https://user@mirror:8080/path?query"),
+            "mirror:8080"
+        );
+        assert_eq!(host_of("http://mirror"), "mirror");
+        assert_eq!(host_of("mirror/path"), "mirror");
+    }
+}
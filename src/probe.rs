@@ -0,0 +1,180 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+use crate::mirrors::host_of;
+use http::HeaderMap;
+
+/// What was learned about a file from the server before fetching it.
+pub(crate) struct Probe {
+    pub length: Option<u64>,
+    pub modified: Option<HttpDate>,
+    /// Whether the server honors range requests, if the probe requested a range of a
+    /// file which has one.
+    pub ranges: Option<bool>,
+    pub headers: HeaderMap,
+    pub effective: Option<EffectiveUri>,
+    /// The response to a probe whose body is the whole file, which the file may be
+    /// fetched from instead of sending another request.
+    pub response: Option<HttpResponse<Body>>,
+}
+
+impl<Data: Send + Sync + 'static, C: HttpBackend> Fetcher<Data, C> {
+    /// Requests the length, validators and range support of a file.
+    ///
+    /// Servers which reject `HEAD` requests, such as those serving presigned URLs, are
+    /// probed with a `GET` request for the first byte of the file instead. Their hosts
+    /// are remembered, so that later probes go straight to the `GET` request. Either
+    /// request waits for a response for up to the fetcher's `timeout`.
+    pub(crate) async fn probe(&self, uri: &str) -> Result<Option<Probe>, Error> {
+        let host = host_of(uri);
+
+        if !self.head_rejected.lock().unwrap().contains(host) {
+            let request = HttpRequest::head(uri).body(()).unwrap();
+
+            match validate(self.dispatch(request, self.timeout).await?) {
+                Ok(response) => return Ok(Some(Probe::from_head(response))),
+                Err(Error::Status(StatusCode::NOT_MODIFIED)) => return Ok(None),
+                Err(Error::Status(status)) if rejects_head(status) => {
+                    info!("{} rejected HEAD with {}, probing with GET", host, status);
+                }
+                Err(why) => return Err(why),
+            }
+        }
+
+        let request = HttpRequest::get(uri)
+            .header("Range", range::to_string(0, Some(0)))
+            .body(())
+            .unwrap();
+
+        let response = self.dispatch(request, self.timeout).await?;
+
+        // An empty file has no first byte to request.
+        let response = if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            response
+        } else {
+            validate(response)?
+        };
+
+        self.head_rejected.lock().unwrap().insert(host.into());
+
+        Ok(Some(Probe::from_get(response)))
+    }
+}
+
+impl Probe {
+    fn from_head(response: HttpResponse<Body>) -> Self {
+        Probe {
+            length: response.content_length(),
+            modified: response.last_modified(),
+            ranges: None,
+            headers: response.headers().clone(),
+            effective: response.extensions().get().cloned(),
+            response: None,
+        }
+    }
+
+    /// Learns about a file from the response to a request for its first byte.
+    fn from_get(response: HttpResponse<Body>) -> Self {
+        let mut probe = Probe {
+            length: None,
+            modified: response.last_modified(),
+            ranges: Some(false),
+            headers: response.headers().clone(),
+            effective: response.extensions().get().cloned(),
+            response: None,
+        };
+
+        let content_range = response
+            .headers()
+            .get("Content-Range")
+            .and_then(|header| header.to_str().ok());
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                // The body of a partial response, `bytes 0-0/{length}`, is not needed.
+                if let Some(range) =
+                    content_range.and_then(|header| header.strip_prefix("bytes 0-"))
+                {
+                    probe.ranges = Some(true);
+                    probe.length = total_length(range);
+                }
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                probe.length = content_range.and_then(total_length);
+            }
+            // The range was ignored, and the whole file is being sent.
+            _ => {
+                probe.length = response.content_length();
+                probe.response = Some(response);
+            }
+        }
+
+        probe
+    }
+}
+
+/// Statuses with which servers reject `HEAD` requests for files that they would serve
+/// with a `GET` request.
+fn rejects_head(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::FORBIDDEN | StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+    )
+}
+
+/// The length of the file from the end of a `Content-Range` header, if it is known.
+fn total_length(content_range: &str) -> Option<u64> {
+    let (_, length) = content_range.rsplit_once('/')?;
+    length.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, block_on, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use http::Method;
+
+    #[test]
+    fn hosts_which_reject_head_are_probed_with_ranged_get() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://mirror/a", MockFile::new(contents(4096)));
+        backend.serve("http://mirror/b", MockFile::new(contents(8192)));
+
+        let status = Fault::status(StatusCode::METHOD_NOT_ALLOWED);
+        backend.fault("http://mirror/a", Some(Method::HEAD), status);
+
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = support::fetcher(&backend).build();
+
+        for (name, length) in [("a", 4096), ("b", 8192)] {
+            let uri = ["http://mirror/", name].concat();
+            let dest = dir.path().join(name);
+            let request = fetcher.clone().request(
+                Arc::from(vec![Box::from(uri)]),
+                Arc::from(dest.as_path()),
+                Arc::new(()),
+            );
+
+            block_on(request).unwrap();
+            assert_eq!(std::fs::read(&dest).unwrap(), contents(length));
+        }
+
+        let requests = backend.requests();
+        let heads = requests.iter().filter(|r| r.method == Method::HEAD);
+
+        // The host is remembered, so that the second file is not probed with `HEAD`.
+        assert_eq!(heads.count(), 1);
+
+        // Each file is probed by requesting its first byte.
+        for uri in ["http://mirror/a", "http://mirror/b"] {
+            let probe = requests
+                .iter()
+                .find(|r| r.method == Method::GET && &*r.uri == uri)
+                .unwrap();
+
+            assert_eq!(probe.headers.get("range").unwrap(), "bytes=0-0");
+        }
+    }
+}
@@ -0,0 +1,469 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::FetchEvent;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The weight given to the newest sample in the smoothed transfer rate.
+const SMOOTHING: f64 = 0.3;
+
+/// How long bytes are collected for before they are sampled into the transfer rate.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The state of a file in the progress tracker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileState {
+    /// The file is being fetched.
+    Fetching,
+    /// The fetch was paused.
+    Paused,
+    /// The fetch is waiting to be attempted again.
+    Retrying,
+    /// The file was fetched, or was already up to date.
+    Fetched,
+    /// The fetch failed.
+    Failed,
+}
+
+/// A snapshot of the progress of a single file.
+#[derive(Clone, Copy, Debug)]
+pub struct FileProgress {
+    /// Bytes of the file which have been fetched, including those resumed from a
+    /// previous attempt.
+    pub fetched: u64,
+    /// The length of the file, if it is known.
+    pub length: Option<u64>,
+    /// Bytes of the file which were fetched by a previous attempt.
+    pub resumed: u64,
+    /// Smoothed rate at which the file is being received, in bytes per second.
+    pub rate: f64,
+    /// Estimated time until the file is fetched, if its length and rate are known.
+    pub eta: Option<Duration>,
+    /// Connections which are currently receiving the file.
+    pub connections: usize,
+    /// Whether the file is being fetched, or has finished.
+    pub state: FileState,
+}
+
+/// A snapshot of the progress of every file in a tracker.
+#[derive(Clone, Debug, Default)]
+pub struct ProgressSnapshot {
+    /// The progress of each file, in no particular order.
+    pub files: Vec<(Arc<Path>, FileProgress)>,
+    /// Bytes fetched across every file.
+    pub fetched: u64,
+    /// The combined length of every file whose length is known.
+    pub length: u64,
+    /// Combined rate at which files are being received, in bytes per second.
+    pub rate: f64,
+    /// Estimated time until every file is fetched, if the length of each file which has
+    /// not finished is known.
+    pub eta: Option<Duration>,
+    /// Connections which are currently receiving files.
+    pub connections: usize,
+}
+
+struct FileTracker {
+    progress: FileProgress,
+    /// Bytes of a resumed fetch which are reported as progress, but were not received.
+    unrated: u64,
+    /// Bytes received since the last sample was taken.
+    sampled: u64,
+    sampled_at: Instant,
+    /// Whether a single connection is receiving the file, rather than parts of it.
+    transferring: bool,
+    parts: usize,
+}
+
+impl FileTracker {
+    fn new() -> Self {
+        Self {
+            progress: FileProgress {
+                fetched: 0,
+                length: None,
+                resumed: 0,
+                rate: 0.0,
+                eta: None,
+                connections: 0,
+                state: FileState::Fetching,
+            },
+            unrated: 0,
+            sampled: 0,
+            sampled_at: Instant::now(),
+            transferring: false,
+            parts: 0,
+        }
+    }
+
+    /// Progress is reported again from the start when a fetch is attempted again.
+    fn restart(&mut self, state: FileState) {
+        self.progress.fetched = 0;
+        self.progress.resumed = 0;
+        self.progress.state = state;
+        self.unrated = 0;
+        self.sampled = 0;
+        self.sampled_at = Instant::now();
+        self.transferring = false;
+        self.parts = 0;
+    }
+
+    fn stop(&mut self, state: FileState) {
+        self.progress.state = state;
+        self.progress.rate = 0.0;
+        self.transferring = false;
+        self.parts = 0;
+    }
+
+    fn update(&mut self, event: &FetchEvent) {
+        match event {
+            FetchEvent::Fetching => *self = Self::new(),
+            FetchEvent::ContentLength(length) => {
+                self.progress.length = Some(*length);
+                self.progress.state = FileState::Fetching;
+                self.transferring = true;
+            }
+            FetchEvent::Resumed(offset) => {
+                self.progress.resumed = *offset;
+                self.unrated = offset.saturating_sub(self.progress.fetched);
+            }
+            FetchEvent::Progress(bytes) => {
+                let unrated = self.unrated.min(*bytes);
+                self.unrated -= unrated;
+                self.progress.fetched += bytes;
+                self.progress.state = FileState::Fetching;
+                self.transferring = true;
+                self.sample(bytes - unrated);
+            }
+            FetchEvent::PartStarted(..) => self.parts += 1,
+            FetchEvent::PartFinished(..) => self.parts = self.parts.saturating_sub(1),
+            FetchEvent::Retrying => self.restart(FileState::Retrying),
+            FetchEvent::RetryAfter(_) | FetchEvent::RetryScheduled(..) => {
+                self.stop(FileState::Retrying)
+            }
+            FetchEvent::Paused => self.stop(FileState::Paused),
+            FetchEvent::Unpaused => self.restart(FileState::Fetching),
+            FetchEvent::AlreadyFetched | FetchEvent::Fetched => {
+                self.stop(FileState::Fetched);
+
+                if let Some(length) = self.progress.length {
+                    self.progress.fetched = length;
+                }
+            }
+            FetchEvent::Failed(_) => self.stop(FileState::Failed),
+            FetchEvent::Mirror(_) | FetchEvent::MirrorSwitched(..) => (),
+        }
+
+        self.progress.connections = if self.parts != 0 {
+            self.parts
+        } else {
+            usize::from(self.transferring)
+        };
+    }
+
+    /// Adds received bytes to the current sample, which is folded into the rate once
+    /// enough time has passed.
+    fn sample(&mut self, bytes: u64) {
+        self.sampled += bytes;
+
+        let elapsed = self.sampled_at.elapsed();
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+
+        self.progress.rate = self.rate_after(elapsed);
+        self.sampled = 0;
+        self.sampled_at = Instant::now();
+    }
+
+    /// The smoothed rate, as if a sample of the bytes received so far was taken now.
+    fn rate_after(&self, elapsed: Duration) -> f64 {
+        let rate = self.sampled as f64 / elapsed.as_secs_f64();
+
+        if self.progress.rate == 0.0 {
+            rate
+        } else {
+            self.progress.rate * (1.0 - SMOOTHING) + rate * SMOOTHING
+        }
+    }
+
+    fn snapshot(&self) -> FileProgress {
+        let mut progress = self.progress;
+
+        if progress.state != FileState::Fetching {
+            return progress;
+        }
+
+        // A stalled transfer slows the rate down, even though no progress is reported.
+        let elapsed = self.sampled_at.elapsed();
+        if elapsed >= SAMPLE_INTERVAL * 2 {
+            progress.rate = self.rate_after(elapsed);
+        }
+
+        progress.eta = progress
+            .length
+            .and_then(|length| eta(length.saturating_sub(progress.fetched), progress.rate));
+
+        progress
+    }
+}
+
+/// Tracks the progress of fetches from the events submitted by a fetcher.
+///
+/// Progress events only report the bytes received since the last event, so each
+/// consumer of events would otherwise have to total them per file. The tracker keeps
+/// those totals, accounting for fetches which are attempted again or resumed, and
+/// measures the rate at which each file is received.
+///
+/// ```
+/// use async_fetcher::{FetchEvent, ProgressTracker};
+/// use std::{path::Path, sync::Arc};
+///
+/// let dest: Arc<Path> = Arc::from(Path::new("file"));
+/// let mut tracker = ProgressTracker::default();
+///
+/// tracker.update(&dest, &FetchEvent::Fetching);
+/// tracker.update(&dest, &FetchEvent::ContentLength(1000));
+/// tracker.update(&dest, &FetchEvent::Progress(250));
+///
+/// let snapshot = tracker.snapshot();
+/// assert_eq!(snapshot.fetched, 250);
+/// assert_eq!(snapshot.length, 1000);
+/// assert_eq!(snapshot.connections, 1);
+/// ```
+#[derive(Default)]
+pub struct ProgressTracker {
+    files: HashMap<Arc<Path>, FileTracker>,
+}
+
+impl ProgressTracker {
+    /// Updates the progress of a file with an event that was submitted for it.
+    pub fn update(&mut self, dest: &Arc<Path>, event: &FetchEvent) {
+        self.files
+            .entry(dest.clone())
+            .or_insert_with(FileTracker::new)
+            .update(event);
+    }
+
+    /// A snapshot of the progress of a file, if it is being tracked.
+    pub fn file(&self, dest: &Path) -> Option<FileProgress> {
+        self.files.get(dest).map(FileTracker::snapshot)
+    }
+
+    /// Stops tracking a file.
+    pub fn remove(&mut self, dest: &Path) -> Option<FileProgress> {
+        self.files.remove(dest).map(|file| file.snapshot())
+    }
+
+    /// Stops tracking files which were fetched, or failed to be fetched.
+    pub fn remove_finished(&mut self) {
+        self.files.retain(|_, file| {
+            !matches!(file.progress.state, FileState::Fetched | FileState::Failed)
+        });
+    }
+
+    /// A snapshot of the progress of every tracked file, and their totals.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let mut snapshot = ProgressSnapshot::default();
+
+        // Bytes left to fetch of the files which have not finished.
+        let mut remaining = Some(0);
+
+        for (dest, file) in &self.files {
+            let progress = file.snapshot();
+
+            snapshot.fetched += progress.fetched;
+            snapshot.length += progress.length.unwrap_or(0);
+            snapshot.rate += progress.rate;
+            snapshot.connections += progress.connections;
+
+            if !matches!(progress.state, FileState::Fetched | FileState::Failed) {
+                remaining = remaining.and_then(|remaining: u64| {
+                    let length = progress.length?;
+                    Some(remaining + length.saturating_sub(progress.fetched))
+                });
+            }
+
+            snapshot.files.push((dest.clone(), progress));
+        }
+
+        snapshot.eta = remaining.and_then(|remaining| eta(remaining, snapshot.rate));
+
+        snapshot
+    }
+}
+
+/// The time it will take to receive the remaining bytes at a rate.
+fn eta(remaining: u64, rate: f64) -> Option<Duration> {
+    if remaining == 0 {
+        return Some(Duration::ZERO);
+    }
+
+    if rate <= 0.0 {
+        return None;
+    }
+
+    Some(Duration::from_secs_f64(remaining as f64 / rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "http://mirror/file";
+
+    fn dest() -> Arc<Path> {
+        Arc::from(Path::new("file"))
+    }
+
+    fn tracker(events: &[FetchEvent]) -> ProgressTracker {
+        let mut tracker = ProgressTracker::default();
+        for event in events {
+            tracker.update(&dest(), event);
+        }
+
+        tracker
+    }
+
+    /// Moves the start of the current sample of a file back in time.
+    fn backdate(tracker: &mut ProgressTracker, elapsed: Duration) {
+        let file = tracker.files.get_mut(&dest()).unwrap();
+        file.sampled_at = Instant::now().checked_sub(elapsed).unwrap();
+    }
+
+    fn rate(tracker: &ProgressTracker) -> f64 {
+        tracker.file(&dest()).unwrap().rate
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        let error = (actual - expected).abs() / expected;
+        assert!(error < 0.05, "{} is not close to {}", actual, expected);
+    }
+
+    #[test]
+    fn eta_is_the_remaining_bytes_over_the_rate() {
+        assert_eq!(eta(1000, 250.0), Some(Duration::from_secs(4)));
+        assert_eq!(eta(0, 0.0), Some(Duration::ZERO));
+        assert_eq!(eta(1000, 0.0), None);
+    }
+
+    #[test]
+    fn the_first_sample_is_the_rate() {
+        let mut tracker = tracker(&[FetchEvent::Fetching, FetchEvent::ContentLength(10_000)]);
+
+        tracker.update(&dest(), &FetchEvent::Progress(500));
+        assert_eq!(rate(&tracker), 0.0, "sampled before the interval passed");
+
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(500));
+        assert_close(rate(&tracker), 1000.0);
+    }
+
+    #[test]
+    fn later_samples_are_smoothed_into_the_rate() {
+        let mut tracker = tracker(&[FetchEvent::Fetching, FetchEvent::ContentLength(10_000)]);
+
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(1000));
+        assert_close(rate(&tracker), 1000.0);
+
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(2000));
+        assert_close(rate(&tracker), 1000.0 * 0.7 + 2000.0 * 0.3);
+    }
+
+    #[test]
+    fn stalled_transfers_slow_the_rate_down() {
+        let mut tracker = tracker(&[FetchEvent::Fetching, FetchEvent::ContentLength(10_000)]);
+
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(1000));
+
+        backdate(&mut tracker, Duration::from_secs(2));
+        assert_close(rate(&tracker), 700.0);
+    }
+
+    #[test]
+    fn resumed_bytes_are_not_counted_in_the_rate() {
+        let mut tracker = tracker(&[
+            FetchEvent::Fetching,
+            FetchEvent::ContentLength(10_000),
+            FetchEvent::Resumed(6000),
+        ]);
+
+        tracker.update(&dest(), &FetchEvent::Progress(6000));
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(1000));
+
+        let progress = tracker.file(&dest()).unwrap();
+        assert_eq!(progress.fetched, 7000);
+        assert_eq!(progress.resumed, 6000);
+        assert_close(progress.rate, 1000.0);
+    }
+
+    #[test]
+    fn eta_follows_the_rate_and_remaining_length() {
+        let mut tracker = tracker(&[FetchEvent::Fetching, FetchEvent::ContentLength(5000)]);
+
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(1000));
+
+        let eta = tracker.file(&dest()).unwrap().eta.unwrap();
+        assert_close(eta.as_secs_f64(), 4.0);
+
+        let snapshot = tracker.snapshot();
+        assert_close(snapshot.eta.unwrap().as_secs_f64(), 4.0);
+    }
+
+    #[test]
+    fn totals_have_no_eta_while_a_length_is_unknown() {
+        let mut tracker = tracker(&[FetchEvent::Fetching, FetchEvent::ContentLength(5000)]);
+        backdate(&mut tracker, Duration::from_secs(1));
+        tracker.update(&dest(), &FetchEvent::Progress(1000));
+
+        let other: Arc<Path> = Arc::from(Path::new("other"));
+        tracker.update(&other, &FetchEvent::Fetching);
+
+        let snapshot = tracker.snapshot();
+        assert_eq!(snapshot.length, 5000);
+        assert_eq!(snapshot.eta, None);
+    }
+
+    #[test]
+    fn retrying_starts_the_progress_over() {
+        let tracker = tracker(&[
+            FetchEvent::Fetching,
+            FetchEvent::ContentLength(5000),
+            FetchEvent::Progress(3000),
+            FetchEvent::Retrying,
+            FetchEvent::Progress(1000),
+        ]);
+
+        let progress = tracker.file(&dest()).unwrap();
+        assert_eq!(progress.fetched, 1000);
+        assert_eq!(progress.length, Some(5000));
+    }
+
+    #[test]
+    fn connections_are_counted_from_the_parts_being_fetched() {
+        let mut tracker = tracker(&[
+            FetchEvent::Fetching,
+            FetchEvent::ContentLength(5000),
+            FetchEvent::PartStarted(0..2500, Box::from(URI)),
+            FetchEvent::PartStarted(2500..5000, Box::from(URI)),
+        ]);
+
+        assert_eq!(tracker.snapshot().connections, 2);
+
+        tracker.update(&dest(), &FetchEvent::PartFinished(0..2500, Box::from(URI)));
+        assert_eq!(tracker.snapshot().connections, 1);
+
+        tracker.update(&dest(), &FetchEvent::Fetched);
+        let snapshot = tracker.snapshot();
+        assert_eq!(snapshot.connections, 0);
+        assert_eq!(snapshot.fetched, 5000);
+        assert_eq!(snapshot.rate, 0.0);
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use futures::future::{self, Either};
+use futures::stream::{self, Fuse, FusedStream, FuturesUnordered, Stream, StreamExt};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+
+/// An input waiting for a free slot.
+struct Queued<T> {
+    priority: i32,
+    sequence: u64,
+    input: T,
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.sequence == other.sequence
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Queued<T> {
+    /// Higher priorities first, then the order in which they were queued.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+struct State<I, T, P, F, Fut> {
+    inputs: Pin<Box<Fuse<I>>>,
+    queue: BinaryHeap<Queued<T>>,
+    running: FuturesUnordered<Fut>,
+    sequence: u64,
+    priority: P,
+    start: F,
+}
+
+/// Runs a future for each input, with up to `concurrent` of them running at a time.
+///
+/// Inputs are taken from the stream as soon as they are available, rather than when a
+/// slot is free, and queued by their priority. No more than `read_ahead` inputs are
+/// queued at a time, and the stream is not polled again until one of them starts.
+/// When a slot is freed, the queued input with the highest priority is started, so an
+/// input with a high priority which arrives late will start before every input of a
+/// lower priority that is still waiting. Inputs of the same priority are started in
+/// the order that they arrived.
+pub(crate) fn prioritized<I, T, P, F, Fut>(
+    inputs: I,
+    concurrent: usize,
+    read_ahead: usize,
+    priority: P,
+    start: F,
+) -> impl Stream<Item = Fut::Output> + Send
+where
+    I: Stream<Item = T> + Send + 'static,
+    T: Send + 'static,
+    P: Fn(&T) -> i32 + Send + 'static,
+    F: FnMut(T) -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send,
+{
+    let concurrent = concurrent.max(1);
+    let read_ahead = read_ahead.max(1);
+
+    let state = State {
+        inputs: Box::pin(inputs.fuse()),
+        queue: BinaryHeap::new(),
+        running: FuturesUnordered::new(),
+        sequence: 0,
+        priority,
+        start,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+        loop {
+            while state.running.len() < concurrent {
+                match state.queue.pop() {
+                    Some(queued) => state.running.push((state.start)(queued.input)),
+                    None => break,
+                }
+            }
+
+            let input = if state.inputs.is_terminated() || state.queue.len() >= read_ahead {
+                let output = state.running.next().await?;
+                return Some((output, state));
+            } else if state.running.is_empty() {
+                state.inputs.next().await
+            } else {
+                match future::select(state.inputs.next(), state.running.next()).await {
+                    Either::Left((input, _)) => input,
+                    Either::Right((Some(output), _)) => return Some((output, state)),
+                    Either::Right((None, _)) => continue,
+                }
+            };
+
+            if let Some(input) = input {
+                state.queue.push(Queued {
+                    priority: (state.priority)(&input),
+                    sequence: state.sequence,
+                    input,
+                });
+
+                state.sequence += 1;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn read_ahead_is_bounded() {
+        let taken = Arc::new(AtomicUsize::new(0));
+
+        let inputs = {
+            let taken = taken.clone();
+            stream::iter(0..100).inspect(move |_| {
+                taken.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        let outputs = prioritized(inputs, 2, 4, |_| 0, future::ready);
+        futures::pin_mut!(outputs);
+
+        let first = futures::executor::block_on(outputs.next());
+
+        assert_eq!(first, Some(0));
+        assert!(taken.load(Ordering::SeqCst) <= 2 + 4 + 1);
+
+        let rest: Vec<_> = futures::executor::block_on(outputs.collect());
+        assert_eq!(rest, (1..100).collect::<Vec<_>>());
+    }
+}
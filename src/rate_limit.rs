@@ -0,0 +1,147 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limits the rate at which bytes are fetched, using a token bucket.
+///
+/// The bucket holds up to one second of tokens, which refill at the configured rate.
+/// Each connection takes tokens for the bytes that it reads, and waits when the bucket
+/// runs dry. A limiter may be shared by any number of connections, and its limit may be
+/// changed while they are running.
+///
+/// ```
+/// use async_fetcher::RateLimiter;
+///
+/// let limiter = RateLimiter::new(Some(1024 * 1024));
+/// assert_eq!(limiter.limit(), Some(1024 * 1024));
+///
+/// limiter.set_limit(None);
+/// assert_eq!(limiter.limit(), None);
+/// ```
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Bytes per second, or unlimited.
+    rate: Option<u64>,
+    /// Bytes which may be read without waiting. Negative when connections are waiting.
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+
+        if let Some(updated) = self.updated {
+            let elapsed = now.duration_since(updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+
+        self.updated = Some(now);
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter of `bytes_per_second`, or an unlimited limiter if `None`.
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        let limiter = Self::default();
+        limiter.set_limit(bytes_per_second);
+        limiter
+    }
+
+    /// The current limit in bytes per second, if any.
+    pub fn limit(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the limit, taking effect immediately for every connection using it.
+    ///
+    /// A limit of zero is treated as no limit.
+    pub fn set_limit(&self, bytes_per_second: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        bucket.rate = bytes_per_second.filter(|&rate| rate != 0);
+        bucket.tokens = 0.0;
+        bucket.updated = Some(Instant::now());
+    }
+
+    /// Takes tokens for `bytes`, returning how long to wait before they may be used.
+    pub(crate) fn take(&self, bytes: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let rate = match bucket.rate {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+
+        bucket.refill(rate);
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, contents};
+    use crate::testing::{MockBackend, MockFile};
+    use std::sync::Arc;
+
+    #[test]
+    fn unlimited_limiters_never_wait() {
+        assert_eq!(RateLimiter::new(None).take(1 << 40), Duration::ZERO);
+        assert_eq!(RateLimiter::new(Some(0)).take(1 << 40), Duration::ZERO);
+    }
+
+    #[test]
+    fn waits_grow_with_the_bytes_taken() {
+        let limiter = RateLimiter::new(Some(1000));
+
+        let first = limiter.take(500);
+        let second = limiter.take(500);
+
+        assert!(first > Duration::from_millis(450) && first <= Duration::from_millis(500));
+        assert!(second > Duration::from_millis(950) && second <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn tokens_refill_up_to_one_second_of_bytes() {
+        let limiter = RateLimiter::new(Some(1000));
+        limiter.bucket.lock().unwrap().updated =
+            Instant::now().checked_sub(Duration::from_secs(10));
+
+        // Ten seconds passed, but only one second of bytes may be read without waiting.
+        assert_eq!(limiter.take(1000), Duration::ZERO);
+        assert!(limiter.take(100) > Duration::from_millis(50));
+    }
+
+    #[test]
+    fn fetches_are_throttled() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://mirror/file", MockFile::new(contents(48 * 1024)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = support::fetcher(&backend);
+        fetcher.rate_limit().set_limit(Some(64 * 1024));
+
+        let started = Instant::now();
+        support::fetch(fetcher, &["http://mirror/file"], &dest).unwrap();
+
+        // The bucket starts empty, so 48 KiB take at least three quarters of a second.
+        assert!(started.elapsed() >= Duration::from_millis(700));
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(48 * 1024));
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::EffectiveUri;
+use http::HeaderMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// How a fetch was completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchOutcome {
+    /// The file was fetched from the start.
+    Fetched,
+    /// The file was fetched, continuing from data fetched by an earlier attempt.
+    Resumed,
+    /// The destination was already the same as the remote file, and was not fetched.
+    UpToDate,
+}
+
+/// Describes how a file was fetched, once it has been fetched.
+#[derive(Clone, Debug)]
+pub struct FetchReport {
+    /// Whether the file was fetched, resumed, or already up to date.
+    pub outcome: FetchOutcome,
+    /// Bytes received from servers, including those of attempts which failed.
+    pub transferred: u64,
+    /// Bytes which were fetched by an earlier attempt, and were not fetched again.
+    pub resumed: u64,
+    /// The URL that the file was last received from, after following redirects.
+    pub url: Option<Box<str>>,
+    /// The mirrors which the file was requested from, in the order they were first used.
+    pub mirrors: Vec<Box<str>>,
+    /// How many times the fetch was attempted again after failing.
+    pub retries: u32,
+    /// The time taken to fetch the file, including retries.
+    pub elapsed: Duration,
+    /// The `ETag` that the server sent for the file.
+    pub etag: Option<Box<str>>,
+    /// The `Last-Modified` time that the server sent for the file.
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Collects the details of a fetch from each of its attempts.
+#[derive(Debug, Default)]
+pub(crate) struct ReportRecorder {
+    transferred: AtomicU64,
+    details: Mutex<Details>,
+}
+
+#[derive(Debug, Default)]
+struct Details {
+    up_to_date: bool,
+    resumed: u64,
+    url: Option<Box<str>>,
+    mirrors: Vec<Box<str>>,
+    retries: u32,
+    etag: Option<Box<str>>,
+    last_modified: Option<SystemTime>,
+}
+
+impl ReportRecorder {
+    /// Records bytes which were received from a server.
+    pub fn transferred(&self, bytes: u64) {
+        self.transferred.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records the bytes that an attempt continued from.
+    pub fn resumed(&self, bytes: u64) {
+        self.details.lock().unwrap().resumed = bytes;
+    }
+
+    /// Whether the current attempt continued from data fetched by an earlier attempt.
+    pub fn is_resumed(&self) -> bool {
+        self.details.lock().unwrap().resumed != 0
+    }
+
+    /// Records a new attempt, which may resume from the data of earlier attempts.
+    pub fn attempt(&self) {
+        let mut details = self.details.lock().unwrap();
+        details.resumed = 0;
+        details.up_to_date = false;
+    }
+
+    pub fn retried(&self) {
+        self.details.lock().unwrap().retries += 1;
+    }
+
+    pub fn up_to_date(&self) {
+        self.details.lock().unwrap().up_to_date = true;
+    }
+
+    /// Records a response from a mirror, with the validators that it sent for the file.
+    pub fn response(&self, uri: &str, headers: &HeaderMap, effective: Option<&EffectiveUri>) {
+        let mut details = self.details.lock().unwrap();
+
+        if !details.mirrors.iter().any(|mirror| &**mirror == uri) {
+            details.mirrors.push(uri.into());
+        }
+
+        details.url = Some(match effective {
+            Some(EffectiveUri(effective)) => effective.to_string().into(),
+            None => uri.into(),
+        });
+
+        if let Some(etag) = headers.get("etag").and_then(|etag| etag.to_str().ok()) {
+            details.etag = Some(etag.into());
+        }
+
+        let last_modified = headers
+            .get("last-modified")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| httpdate::parse_http_date(header).ok());
+
+        if let Some(last_modified) = last_modified {
+            details.last_modified = Some(last_modified);
+        }
+    }
+
+    pub fn report(&self, elapsed: Duration) -> FetchReport {
+        let details = self.details.lock().unwrap();
+
+        let outcome = if details.up_to_date {
+            FetchOutcome::UpToDate
+        } else if details.resumed != 0 {
+            FetchOutcome::Resumed
+        } else {
+            FetchOutcome::Fetched
+        };
+
+        FetchReport {
+            outcome,
+            transferred: self.transferred.load(Ordering::Relaxed),
+            resumed: details.resumed,
+            url: details.url.clone(),
+            mirrors: details.mirrors.clone(),
+            retries: details.retries,
+            elapsed,
+            etag: details.etag.clone(),
+            last_modified: details.last_modified,
+        }
+    }
+}
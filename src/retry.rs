@@ -0,0 +1,414 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::Error;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The category of an error, used to decide how it should be retried.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The connection to the server could not be established or was lost.
+    Connection,
+    /// The server stopped sending data for longer than the configured timeout.
+    Timeout,
+    /// The network interfaces of the system changed while fetching.
+    NetworkChanged,
+    /// The server responded with a 5xx status.
+    ServerError,
+    /// The server responded with a 4xx status.
+    ClientError,
+    /// A local I/O error occurred while writing the fetched file.
+    Io,
+    /// Any other error.
+    Other,
+}
+
+impl ErrorClass {
+    /// Categorizes an error from the fetcher.
+    pub fn of(error: &Error) -> Self {
+        match error {
+            Error::Context(_, error) => ErrorClass::of(error),
+            Error::NetworkChanged => ErrorClass::NetworkChanged,
+            Error::TimedOut => ErrorClass::Timeout,
+            Error::Read(_) => ErrorClass::Connection,
+            Error::Backend(why) => match why.downcast_ref::<io::Error>() {
+                Some(why) => ErrorClass::of_io(why),
+                None => ErrorClass::Other,
+            },
+            #[cfg(feature = "isahc")]
+            Error::IsahcClient(why) if why.is_network() => ErrorClass::Connection,
+            #[cfg(feature = "isahc")]
+            Error::IsahcClient(why) if why.is_timeout() => ErrorClass::Timeout,
+            #[cfg(feature = "reqwest")]
+            Error::ReqwestClient(why) if why.is_connect() || why.is_request() => {
+                ErrorClass::Connection
+            }
+            #[cfg(feature = "reqwest")]
+            Error::ReqwestClient(why) if why.is_timeout() => ErrorClass::Timeout,
+            Error::Status(status) | Error::RetryAfter(status, _) if status.is_server_error() => {
+                ErrorClass::ServerError
+            }
+            Error::Status(status) | Error::RetryAfter(status, _) if status.is_client_error() => {
+                ErrorClass::ClientError
+            }
+            Error::Concatenate(_)
+            | Error::FileCreate(_)
+            | Error::FileTime(..)
+            | Error::MetadataRemove(_)
+            | Error::OpenPart(..)
+            | Error::Write(_)
+            | Error::Rename(_) => ErrorClass::Io,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    /// Categorizes an I/O error returned by an HTTP backend.
+    fn of_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::AddrInUse
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof => ErrorClass::Connection,
+            io::ErrorKind::TimedOut => ErrorClass::Timeout,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+/// How the fetcher should respond to an error of a given class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Retry, counting the attempt against the fetcher's `retries`.
+    Retry,
+    /// Retry without counting the attempt against the fetcher's `retries`.
+    RetryUnlimited,
+    /// Fail the fetch immediately.
+    Abort,
+}
+
+/// Controls the delays between attempts, and which errors are retried.
+///
+/// The delay before a retry grows exponentially from `base_delay` by `multiplier`
+/// with each consecutive failed attempt, up to `max_delay`. A random portion of each
+/// delay, defined by `jitter`, is subtracted so that many clients which failed at
+/// the same time do not retry in lockstep. The count of consecutive failures is reset
+/// whenever a fetch makes progress.
+#[derive(Clone, Debug, Setters)]
+pub struct RetryPolicy {
+    /// The delay before the first retry.
+    /// # Note
+    /// Defaults to 3 seconds.
+    base_delay: Duration,
+
+    /// The factor that the delay is multiplied by for each consecutive failure.
+    /// # Note
+    /// Defaults to 2.
+    multiplier: f64,
+
+    /// The upper limit of the delay between attempts.
+    /// # Note
+    /// Defaults to 60 seconds.
+    max_delay: Duration,
+
+    /// The fraction of each delay, between `0.0` and `1.0`, which is randomized.
+    /// # Note
+    /// Defaults to 0.5.
+    jitter: f64,
+
+    /// The maximum time to keep retrying after the first of a series of failures,
+    /// including the time spent waiting for servers which asked to be retried later.
+    /// # Note
+    /// Defaults to retrying indefinitely.
+    #[setters(strip_option)]
+    max_elapsed: Option<Duration>,
+
+    /// The number of times connectivity to the server is checked after a timeout or
+    /// network change, before retrying anyway.
+    /// # Note
+    /// Defaults to 5.
+    probes: u16,
+
+    /// The longest that the fetcher will honor a `Retry-After` header for.
+    /// # Note
+    /// Defaults to 5 minutes.
+    max_retry_after: Duration,
+
+    /// The number of consecutive times to wait for servers which asked to be retried
+    /// later, which are not counted against the fetcher's `retries`.
+    /// # Note
+    /// Defaults to 10.
+    max_retry_after_waits: u16,
+
+    #[setters(skip)]
+    decisions: HashMap<ErrorClass, RetryDecision>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        let mut policy = Self {
+            base_delay: Duration::from_secs(3),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.5,
+            max_elapsed: None,
+            probes: 5,
+            max_retry_after: Duration::from_secs(5 * 60),
+            max_retry_after_waits: 10,
+            decisions: HashMap::new(),
+        };
+
+        policy.decisions.extend([
+            (ErrorClass::Connection, RetryDecision::RetryUnlimited),
+            (ErrorClass::Timeout, RetryDecision::RetryUnlimited),
+            (ErrorClass::NetworkChanged, RetryDecision::RetryUnlimited),
+        ]);
+
+        policy
+    }
+}
+
+impl RetryPolicy {
+    /// Sets how errors of the given class are handled.
+    ///
+    /// Errors of classes without a decision are retried with `RetryDecision::Retry` if
+    /// they are retryable, as defined by `Error::is_retryable`, and abort otherwise.
+    pub fn on(mut self, class: ErrorClass, decision: RetryDecision) -> Self {
+        self.decisions.insert(class, decision);
+        self
+    }
+
+    /// How the given error is handled.
+    pub fn decision(&self, error: &Error) -> RetryDecision {
+        match self.decisions.get(&ErrorClass::of(error)) {
+            Some(&decision) => decision,
+            None if error.is_retryable() => RetryDecision::Retry,
+            None => RetryDecision::Abort,
+        }
+    }
+
+    /// The maximum time to keep retrying after the first of a series of failures.
+    pub fn max_elapsed_time(&self) -> Option<Duration> {
+        self.max_elapsed
+    }
+
+    /// The number of connectivity checks to make after a timeout or network change.
+    pub fn probe_count(&self) -> u16 {
+        self.probes
+    }
+
+    /// The longest that the fetcher will honor a `Retry-After` header for.
+    pub fn max_retry_after_delay(&self) -> Duration {
+        self.max_retry_after
+    }
+
+    /// The number of consecutive times to wait for servers which asked to be retried later.
+    pub fn max_retry_after_wait_count(&self) -> u16 {
+        self.max_retry_after_waits
+    }
+
+    /// The delay before retrying after `attempt` consecutive failures.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+
+        Duration::from_secs_f64(delay * (1.0 - jitter * random))
+    }
+}
+
+/// The consecutive failures of a fetch, which are forgotten once it makes progress.
+#[derive(Debug, Default)]
+pub(crate) struct Attempts {
+    failed: AtomicU16,
+    /// Waits for servers which asked to be retried later.
+    waited: AtomicU16,
+    /// When the first of the failures occurred, including those of servers which asked
+    /// to be retried later, which are not counted as failed attempts.
+    failing_since: Mutex<Option<Instant>>,
+}
+
+impl Attempts {
+    /// The number of consecutive attempts which have failed.
+    pub fn failed(&self) -> u16 {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Counts a failed attempt, returning the number of consecutive failed attempts.
+    pub fn fail(&self) -> u16 {
+        self.failing_since();
+        self.failed.fetch_add(1, Ordering::SeqCst).saturating_add(1)
+    }
+
+    /// Counts a wait for a server which asked to be retried later, returning the number
+    /// of consecutive waits.
+    pub fn wait(&self) -> u16 {
+        self.failing_since();
+        self.waited.fetch_add(1, Ordering::SeqCst).saturating_add(1)
+    }
+
+    /// When the consecutive failures began, which is now if this is the first of them.
+    pub fn failing_since(&self) -> Instant {
+        *self
+            .failing_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now)
+    }
+
+    /// Forgets the failures, as the fetch has made progress.
+    pub fn progress(&self) {
+        self.failed.store(0, Ordering::SeqCst);
+        self.waited.store(0, Ordering::SeqCst);
+        *self.failing_since.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::support::{self, contents};
+    use crate::testing::{Fault, MockBackend, MockFile};
+    use http::{HeaderMap, HeaderValue, Method, StatusCode};
+    use std::sync::Arc;
+
+    const URI: &str = "http://mirror/file";
+
+    fn overloaded(backend: &MockBackend, retry_after: &'static str, times: usize) {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static(retry_after));
+
+        for _ in 0..times {
+            let fault = Fault::Status(StatusCode::SERVICE_UNAVAILABLE, headers.clone());
+            backend.fault(URI, Some(Method::GET), fault);
+        }
+    }
+
+    fn gets(backend: &MockBackend) -> usize {
+        let requests = backend.requests();
+        requests.iter().filter(|r| r.method == Method::GET).count()
+    }
+
+    fn backend_error(kind: io::ErrorKind) -> Error {
+        Error::Backend(Box::new(io::Error::new(kind, "backend")))
+    }
+
+    #[test]
+    fn backend_errors_are_classified_by_their_kind() {
+        let refused = backend_error(io::ErrorKind::ConnectionRefused);
+        let timed_out = backend_error(io::ErrorKind::TimedOut);
+        let denied = backend_error(io::ErrorKind::PermissionDenied);
+        let unknown = Error::Backend("unknown".into());
+
+        assert_eq!(ErrorClass::of(&refused), ErrorClass::Connection);
+        assert_eq!(ErrorClass::of(&timed_out), ErrorClass::Timeout);
+        assert_eq!(ErrorClass::of(&denied), ErrorClass::Other);
+        assert_eq!(ErrorClass::of(&unknown), ErrorClass::Other);
+
+        // Errors which are not known to be connection failures are retried with a limit.
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.decision(&refused), RetryDecision::RetryUnlimited);
+        assert_eq!(policy.decision(&denied), RetryDecision::Retry);
+        assert_eq!(policy.decision(&unknown), RetryDecision::Retry);
+    }
+
+    #[test]
+    fn delays_grow_to_the_maximum() {
+        let policy = RetryPolicy::default()
+            .base_delay(Duration::from_secs(1))
+            .max_delay(Duration::from_secs(5))
+            .jitter(0.0);
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(4), Duration::from_secs(5));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retry_after_is_not_counted_as_a_retry() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(4096)));
+        overloaded(&backend, "0", 5);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = support::fetcher(&backend).retries(1);
+        let report = support::fetch(fetcher, &[URI], &dest).unwrap();
+
+        assert_eq!(report.retries, 5);
+        assert_eq!(gets(&backend), 6);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(4096));
+    }
+
+    #[test]
+    fn retry_after_waits_are_limited() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(4096)));
+        overloaded(&backend, "0", 20);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let policy = RetryPolicy::default().max_retry_after_waits(3);
+        let fetcher = support::fetcher(&backend).retry_policy(policy);
+        let error = support::fetch(fetcher, &[URI], &dest).unwrap_err();
+
+        assert!(matches!(
+            error.without_context(),
+            Error::RetryAfter(StatusCode::SERVICE_UNAVAILABLE, _)
+        ));
+        assert_eq!(gets(&backend), 4);
+    }
+
+    #[test]
+    fn retry_after_waits_are_limited_by_max_elapsed() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(4096)));
+        overloaded(&backend, "1", 20);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let policy = RetryPolicy::default()
+            .max_retry_after_waits(20)
+            .max_retry_after(Duration::from_millis(20))
+            .max_elapsed(Duration::from_millis(50));
+
+        let fetcher = support::fetcher(&backend).retry_policy(policy);
+        let error = support::fetch(fetcher, &[URI], &dest).unwrap_err();
+
+        assert!(error.is_retryable());
+        assert!(gets(&backend) < 20);
+    }
+
+    #[test]
+    fn aborted_classes_are_not_retried() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve(URI, MockFile::new(contents(4096)));
+        overloaded(&backend, "0", 1);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let policy = RetryPolicy::default().on(ErrorClass::ServerError, RetryDecision::Abort);
+        let fetcher = support::fetcher(&backend).retry_policy(policy);
+
+        assert!(support::fetch(fetcher, &[URI], &dest).is_err());
+        assert_eq!(gets(&backend), 1);
+    }
+}
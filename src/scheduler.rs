@@ -0,0 +1,315 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::control::PartRecord;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Ranges with less than twice this many bytes remaining are not split.
+const MIN_STEAL: u64 = 64 * 1024;
+
+/// A byte range of a file which is fetched by a single connection.
+///
+/// The end of the range shrinks when an idle connection steals a portion of it.
+pub(crate) struct RangePart {
+    /// Identifies the part file that the range is written to.
+    pub id: usize,
+    /// The offset of the first byte of the range.
+    pub start: u64,
+    /// Whether the range was split from another range.
+    pub stolen: bool,
+    state: Mutex<PartState>,
+}
+
+struct PartState {
+    /// The offset of the next byte to be written.
+    position: u64,
+    /// The offset after the last byte which has been written.
+    written: u64,
+    /// The offset after the last byte of the range.
+    end: u64,
+}
+
+impl RangePart {
+    fn new(id: usize, start: u64, position: u64, end: u64) -> Self {
+        Self {
+            id,
+            start,
+            stolen: false,
+            state: Mutex::new(PartState {
+                position,
+                written: position,
+                end,
+            }),
+        }
+    }
+
+    /// The offset of the next byte to be written.
+    pub fn position(&self) -> u64 {
+        self.state.lock().unwrap().position
+    }
+
+    /// The offset after the last byte of the range.
+    pub fn end(&self) -> u64 {
+        self.state.lock().unwrap().end
+    }
+
+    /// Whether every byte of the range has been written.
+    pub fn is_complete(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.position >= state.end
+    }
+
+    /// Claims up to `length` bytes to be written, returning how many may be written.
+    pub fn advance(&self, length: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let length = length.min(state.end.saturating_sub(state.position));
+        state.position += length;
+        length
+    }
+
+    /// Records that `length` of the claimed bytes have been written.
+    pub fn commit(&self, length: u64) {
+        self.state.lock().unwrap().written += length;
+    }
+}
+
+/// Hands out byte ranges of a file to connections.
+///
+/// Ranges are initially handed out in order. Once all of them have been handed out,
+/// idle connections split the range with the most bytes remaining, taking the second
+/// half of it. This prevents the slowest connection from holding up the end of a fetch.
+pub(crate) struct RangeScheduler {
+    inner: Mutex<SchedulerState>,
+}
+
+struct SchedulerState {
+    /// Every range, including those which have finished.
+    all: Vec<Arc<RangePart>>,
+    pending: VecDeque<Arc<RangePart>>,
+    active: Vec<Arc<RangePart>>,
+    next_id: usize,
+}
+
+impl RangeScheduler {
+    /// Schedules inclusive ranges generated by `range::generate`.
+    pub fn new(ranges: impl Iterator<Item = (u64, u64)>) -> Self {
+        Self::from_parts(
+            ranges
+                .enumerate()
+                .map(|(id, (start, end))| RangePart::new(id, start, start, end + 1))
+                .collect(),
+        )
+    }
+
+    /// Schedules previously-recorded parts, which have been fetched up to `position`.
+    pub fn resume(parts: impl Iterator<Item = (PartRecord, u64)>) -> Self {
+        Self::from_parts(
+            parts
+                .map(|(part, position)| RangePart::new(part.id, part.start, position, part.end))
+                .collect(),
+        )
+    }
+
+    fn from_parts(parts: Vec<RangePart>) -> Self {
+        let all: Vec<_> = parts.into_iter().map(Arc::new).collect();
+
+        Self {
+            inner: Mutex::new(SchedulerState {
+                next_id: all.iter().map(|part| part.id + 1).max().unwrap_or(0),
+                pending: all.iter().cloned().collect(),
+                active: Vec::new(),
+                all,
+            }),
+        }
+    }
+
+    /// The current layout of the ranges, for recording in a `ControlFile`.
+    pub fn layout(&self) -> Vec<PartRecord> {
+        let inner = self.inner.lock().unwrap();
+
+        let mut parts: Vec<_> = inner
+            .all
+            .iter()
+            .map(|part| PartRecord {
+                id: part.id,
+                start: part.start,
+                end: part.end(),
+                position: part.state.lock().unwrap().written,
+            })
+            .collect();
+
+        parts.sort_by_key(|part| part.start);
+        parts
+    }
+
+    /// The next range that an idle connection should fetch.
+    pub fn next(&self) -> Option<Arc<RangePart>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.active.retain(|part| !part.is_complete());
+
+        if let Some(part) = inner.pending.pop_front() {
+            inner.active.push(part.clone());
+            return Some(part);
+        }
+
+        let victim = inner.active.iter().max_by_key(|part| {
+            let state = part.state.lock().unwrap();
+            state.end.saturating_sub(state.position)
+        })?;
+
+        let stolen = {
+            let mut state = victim.state.lock().unwrap();
+            let remaining = state.end.saturating_sub(state.position);
+
+            if remaining < 2 * MIN_STEAL {
+                return None;
+            }
+
+            let middle = state.position + remaining / 2;
+            let stolen = RangePart {
+                stolen: true,
+                ..RangePart::new(inner.next_id, middle, middle, state.end)
+            };
+
+            state.end = middle;
+            Arc::new(stolen)
+        };
+
+        inner.next_id += 1;
+        inner.active.push(stolen.clone());
+        inner.all.push(stolen.clone());
+
+        Some(stolen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn record(id: usize, start: u64, end: u64, position: u64) -> PartRecord {
+        PartRecord {
+            id,
+            start,
+            end,
+            position,
+        }
+    }
+
+    #[test]
+    fn ranges_are_handed_out_in_order() {
+        let scheduler = RangeScheduler::new(vec![(0, MIB - 1), (MIB, 2 * MIB - 1)].into_iter());
+
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+
+        assert_eq!((first.start, first.end()), (0, MIB));
+        assert_eq!((second.start, second.end()), (MIB, 2 * MIB));
+        assert!(!first.stolen && !second.stolen);
+    }
+
+    #[test]
+    fn idle_connections_steal_half_of_the_largest_range() {
+        let scheduler = RangeScheduler::new(vec![(0, MIB - 1), (MIB, 2 * MIB - 1)].into_iter());
+
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        first.advance(MIB / 4);
+        second.advance(MIB / 2);
+
+        let stolen = scheduler.next().unwrap();
+
+        assert!(stolen.stolen);
+        assert_eq!(stolen.id, 2);
+        assert_eq!((stolen.start, stolen.end()), (MIB / 2 + MIB / 8, MIB));
+        assert_eq!(first.end(), stolen.start);
+        assert_eq!(second.end(), 2 * MIB);
+    }
+
+    #[test]
+    fn small_ranges_are_not_split() {
+        let scheduler = RangeScheduler::new(vec![(0, 2 * MIN_STEAL - 2)].into_iter());
+        let part = scheduler.next().unwrap();
+
+        assert!(scheduler.next().is_none());
+        assert_eq!(part.end(), 2 * MIN_STEAL - 1);
+
+        let scheduler = RangeScheduler::new(vec![(0, 2 * MIN_STEAL - 1)].into_iter());
+        let part = scheduler.next().unwrap();
+        let stolen = scheduler.next().unwrap();
+
+        assert_eq!(part.end(), MIN_STEAL);
+        assert_eq!((stolen.start, stolen.end()), (MIN_STEAL, 2 * MIN_STEAL));
+    }
+
+    #[test]
+    fn advance_is_capped_by_a_split() {
+        let scheduler = RangeScheduler::new(vec![(0, MIB - 1)].into_iter());
+        let part = scheduler.next().unwrap();
+        part.advance(1000);
+
+        let stolen = scheduler.next().unwrap();
+
+        // The bytes which were read past the new end belong to the stolen range.
+        assert_eq!(part.advance(MIB), stolen.start - 1000);
+        assert!(part.is_complete());
+        assert_eq!(part.advance(1), 0);
+    }
+
+    #[test]
+    fn complete_ranges_are_not_stolen_from() {
+        let scheduler = RangeScheduler::new(vec![(0, MIB - 1), (MIB, MIB + 4095)].into_iter());
+
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        first.advance(MIB);
+        second.advance(1024);
+
+        assert!(scheduler.next().is_none());
+    }
+
+    #[test]
+    fn layout_records_written_bytes_after_a_split() {
+        let scheduler = RangeScheduler::new(vec![(0, MIB - 1)].into_iter());
+        let part = scheduler.next().unwrap();
+
+        // Claimed bytes are only recorded once they have been written.
+        part.advance(3000);
+        part.commit(2000);
+
+        let stolen = scheduler.next().unwrap();
+        stolen.advance(500);
+        stolen.commit(500);
+
+        let middle = stolen.start;
+        assert_eq!(
+            scheduler.layout(),
+            [
+                record(0, 0, middle, 2000),
+                record(1, middle, MIB, middle + 500)
+            ]
+        );
+    }
+
+    #[test]
+    fn resumed_parts_continue_from_their_position() {
+        let parts = [
+            (record(3, 0, MIB, 0), MIB),
+            (record(5, MIB, 2 * MIB, 0), MIB + 100),
+        ];
+
+        let scheduler = RangeScheduler::resume(parts.iter().copied());
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+
+        assert!(first.is_complete());
+        assert_eq!((second.id, second.position()), (5, MIB + 100));
+
+        // New ranges are given ids which no resumed part uses.
+        assert_eq!(scheduler.next().unwrap().id, 6);
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use super::*;
+use crate::report::ReportRecorder;
+use bytes::Bytes;
+use futures::io::{AsyncWrite, AsyncWriteExt};
+
+impl<Data: Send + Sync + 'static, C: HttpBackend> Fetcher<Data, C> {
+    /// Requests a file from one or more URIs, streaming it into `writer`.
+    ///
+    /// Mirrors, retries, timeouts and progress events behave as they do for `request`,
+    /// with `name` identifying the fetch in the events. The file is fetched with a single
+    /// connection. Bytes which were written before a connection failed are not written
+    /// again: the fetch continues with a range request, or skips the bytes already
+    /// written if the server does not support range requests.
+    ///
+    /// Returns the number of bytes written, and a report of how they were fetched.
+    pub async fn request_writer<W: AsyncWrite + Unpin + ?Sized>(
+        self: Arc<Self>,
+        uris: Arc<[Box<str>]>,
+        name: Arc<Path>,
+        writer: &mut W,
+        extra: Arc<Data>,
+    ) -> Result<(u64, FetchReport), Error> {
+        self.sink(uris, name, writer, None, extra).await
+    }
+
+    /// Requests a file from one or more URIs into memory.
+    ///
+    /// Behaves as `request_writer`, failing with `Error::TooLarge` if the file is larger
+    /// than `max_size` bytes.
+    pub async fn request_bytes(
+        self: Arc<Self>,
+        uris: Arc<[Box<str>]>,
+        name: Arc<Path>,
+        max_size: u64,
+        extra: Arc<Data>,
+    ) -> Result<(Bytes, FetchReport), Error> {
+        let mut buffer = Vec::new();
+        let (_, report) = self
+            .sink(uris, name, &mut buffer, Some(max_size), extra)
+            .await?;
+        Ok((Bytes::from(buffer), report))
+    }
+
+    async fn sink<W: AsyncWrite + Unpin + ?Sized>(
+        self: Arc<Self>,
+        uris: Arc<[Box<str>]>,
+        name: Arc<Path>,
+        writer: &mut W,
+        max_size: Option<u64>,
+        extra: Arc<Data>,
+    ) -> Result<(u64, FetchReport), Error> {
+        let result = self
+            .clone()
+            .sink_retrying(uris, name.clone(), writer, max_size, extra.clone())
+            .await;
+
+        if let Err(why) = &result {
+            let reason = context::describe(why).into();
+            self.send(|| (name, extra, FetchEvent::Failed(reason)));
+        }
+
+        result
+    }
+
+    async fn sink_retrying<W: AsyncWrite + Unpin + ?Sized>(
+        self: Arc<Self>,
+        uris: Arc<[Box<str>]>,
+        name: Arc<Path>,
+        writer: &mut W,
+        max_size: Option<u64>,
+        extra: Arc<Data>,
+    ) -> Result<(u64, FetchReport), Error> {
+        crate::utils::shutdown_check(&self.shutdown)?;
+
+        let _token = match self.shutdown.delay_shutdown_token() {
+            Ok(token) => token,
+            Err(_) => return Err(Error::Canceled),
+        };
+
+        self.send(|| (name.clone(), extra.clone(), FetchEvent::Fetching));
+
+        let started = Instant::now();
+        let report = ReportRecorder::default();
+        let attempts = Attempts::default();
+        let mut failover = Failover::new(self.mirror_health.rank(&uris));
+        let mut written = 0;
+
+        loop {
+            self.wait_while_paused(None, &name, &extra).await;
+
+            if let Some(event) = failover.announce() {
+                self.send(|| (name.clone(), extra.clone(), event));
+            }
+
+            let attempt = self.sink_attempt(
+                failover.uri(),
+                &mut *writer,
+                &mut written,
+                max_size,
+                &attempts,
+                &report,
+                &name,
+                &extra,
+            );
+
+            let error = match attempt.await {
+                Ok(()) => break,
+                Err(Error::Paused) => continue,
+                Err(error) => error,
+            };
+
+            let error = error.with_context(|| ErrorContext {
+                url: failover.uri().into(),
+                dest: name.clone(),
+                offset: Some(written),
+                attempt: attempts.failed().saturating_add(1),
+            });
+
+            // Bytes written to the writer can not be taken back if writing fails.
+            if let Error::Write(_) = error.without_context() {
+                return Err(error);
+            }
+
+            self.recover(&mut failover, error, &attempts, &name, &extra)
+                .await?;
+
+            report.retried();
+
+            self.send(|| (name.clone(), extra.clone(), FetchEvent::Retrying));
+        }
+
+        writer.flush().await.map_err(Error::Write)?;
+
+        self.send(|| (name.clone(), extra.clone(), FetchEvent::Fetched));
+
+        Ok((written, report.report(started.elapsed())))
+    }
+
+    /// Fetches the remainder of a file into the writer with one connection.
+    #[allow(clippy::too_many_arguments)]
+    async fn sink_attempt<W: AsyncWrite + Unpin + ?Sized>(
+        &self,
+        uri: &str,
+        writer: &mut W,
+        written: &mut u64,
+        max_size: Option<u64>,
+        attempts: &Attempts,
+        report: &ReportRecorder,
+        name: &Arc<Path>,
+        extra: &Arc<Data>,
+    ) -> Result<(), Error> {
+        report.attempt();
+
+        let mut request = HttpRequest::get(uri);
+
+        if *written != 0 {
+            request = request.header("Range", range::to_string(*written, None));
+        }
+
+        let request = request.body(()).expect("failed to build request");
+        let response = validate(
+            self.dispatch(request, Some(Duration::from_secs(10)))
+                .await?,
+        )?;
+
+        let effective = response.extensions().get();
+        report.response(uri, response.headers(), effective);
+
+        // The bytes already written are skipped if the server sent the whole file.
+        let mut skip = if response.status() == StatusCode::PARTIAL_CONTENT {
+            let expected = ["bytes ", &written.to_string(), "-"].concat();
+
+            let honored = response
+                .headers()
+                .get("Content-Range")
+                .and_then(|header| header.to_str().ok())
+                .is_some_and(|header| header.starts_with(&expected));
+
+            if !honored {
+                let why = io::Error::new(io::ErrorKind::InvalidData, "unexpected content range");
+                return Err(Error::InvalidRange(why));
+            }
+
+            0
+        } else {
+            *written
+        };
+
+        if let Some(length) = response.content_length() {
+            let length = *written - skip + length;
+
+            if let Some(max_size) = max_size.filter(|&max_size| length > max_size) {
+                return Err(Error::TooLarge(max_size));
+            }
+
+            self.send(|| {
+                (
+                    name.clone(),
+                    extra.clone(),
+                    FetchEvent::ContentLength(length),
+                )
+            });
+        }
+
+        if *written != 0 {
+            report.resumed(*written);
+
+            let progress = *written;
+            self.send(|| (name.clone(), extra.clone(), FetchEvent::Resumed(progress)));
+            self.send(|| (name.clone(), extra.clone(), FetchEvent::Progress(progress)));
+        }
+
+        let mut body = response.into_body();
+        let mut buffer = vec![0u8; 8192];
+
+        let mut progress = 0;
+        let mut transferred = 0;
+
+        let started = Instant::now();
+        let mut now = started;
+
+        let result = async {
+            loop {
+                crate::utils::shutdown_check(&self.shutdown)?;
+
+                if self.is_paused() {
+                    return Err(Error::Paused);
+                }
+
+                let bytes_read = async { body.read(&mut buffer).await.map_err(Error::Read) };
+
+                let read = match self.timeout {
+                    Some(timeout) => crate::utils::timed_interrupt(timeout, bytes_read).await,
+                    None => crate::utils::network_interrupt(bytes_read).await,
+                }?;
+
+                if read == 0 {
+                    if skip != 0 {
+                        let why = io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early");
+                        return Err(Error::Read(why));
+                    }
+
+                    return Ok(());
+                }
+
+                let wait = self.rate_limiter.take(read as u64);
+
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+
+                transferred += read as u64;
+
+                let skipped = skip.min(read as u64);
+                skip -= skipped;
+
+                let chunk = &buffer[skipped as usize..read];
+
+                if let Some(max_size) = max_size {
+                    if *written + chunk.len() as u64 > max_size {
+                        return Err(Error::TooLarge(max_size));
+                    }
+                }
+
+                writer.write_all(chunk).await.map_err(Error::Write)?;
+
+                *written += chunk.len() as u64;
+                progress += chunk.len() as u64;
+
+                if now.elapsed().as_millis() as u64 > self.progress_interval {
+                    self.send(|| (name.clone(), extra.clone(), FetchEvent::Progress(progress)));
+
+                    now = Instant::now();
+                    progress = 0;
+                }
+
+                attempts.progress();
+            }
+        };
+
+        let result = result.await;
+
+        report.transferred(transferred);
+
+        match result {
+            Ok(()) => self
+                .mirror_health
+                .record_transfer(uri, transferred, started.elapsed()),
+            Err(Error::TimedOut) | Err(Error::Read(_)) => self.mirror_health.record_failure(uri),
+            Err(_) => (),
+        }
+
+        if result.is_ok() && progress != 0 {
+            self.send(|| (name.clone(), extra.clone(), FetchEvent::Progress(progress)));
+        }
+
+        result
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright 2021-2022 System76 <info@system76.com>
 // SPDX-License-Identifier: MPL-2.0
 
+use crate::report::ReportRecorder;
+use crate::{Checksum, Decompress, FetchHandle, RateLimiter};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -14,7 +16,34 @@ pub struct Source {
     pub dest: Arc<Path>,
 
     /// Where partial files should be stored.
+    ///
+    /// Defaults to the destination with a `.fetching` extension appended.
     pub part: Option<Arc<Path>>,
+
+    /// The expected checksum of the file, which is computed while it is fetched.
+    pub checksum: Option<Checksum>,
+
+    /// Decompresses the source while it is fetched.
+    pub decompress: Option<Decompress>,
+
+    /// Limits the rate at which this source is fetched, in addition to the fetcher's limit.
+    pub rate_limit: Option<Arc<RateLimiter>>,
+
+    /// Sources with a higher priority are fetched before those waiting with a lower one.
+    pub priority: i32,
+
+    /// Cancels the fetch of this source.
+    pub handle: FetchHandle,
+}
+
+/// The options of a `Source` which apply while it is being fetched.
+#[derive(Clone, Default)]
+pub(crate) struct FetchOptions {
+    pub checksum: Option<Checksum>,
+    pub decompress: Option<Decompress>,
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    pub handle: Option<FetchHandle>,
+    pub report: Arc<ReportRecorder>,
 }
 
 impl Source {
@@ -27,6 +56,11 @@ impl Source {
             urls,
             dest,
             part: None,
+            checksum: None,
+            decompress: None,
+            rate_limit: None,
+            priority: 0,
+            handle: FetchHandle::default(),
         }
     }
 
@@ -34,6 +68,39 @@ impl Source {
     pub fn set_part(&mut self, part: Option<Arc<Path>>) {
         self.part = part;
     }
+
+    /// Sets the expected checksum of a source.
+    pub fn set_checksum(&mut self, checksum: Option<Checksum>) {
+        self.checksum = checksum;
+    }
+
+    /// Sets the decompression of a source.
+    pub fn set_decompress(&mut self, decompress: Option<Decompress>) {
+        self.decompress = decompress;
+    }
+
+    /// Sets the rate limit of a source.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<Arc<RateLimiter>>) {
+        self.rate_limit = rate_limit;
+    }
+
+    /// Sets the priority of a source.
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+
+    /// Separates the options which apply while the source is being fetched.
+    pub(crate) fn into_parts(mut self) -> (Self, FetchOptions) {
+        let options = FetchOptions {
+            checksum: self.checksum.take(),
+            decompress: self.decompress.take(),
+            rate_limit: self.rate_limit.take(),
+            handle: Some(self.handle.clone()),
+            report: Arc::default(),
+        };
+
+        (self, options)
+    }
 }
 
 /// Constructs a `Source`.
@@ -41,6 +108,10 @@ pub struct SourceBuilder {
     urls: Vec<Box<str>>,
     dest: Arc<Path>,
     part: Option<Arc<Path>>,
+    checksum: Option<Checksum>,
+    decompress: Option<Decompress>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    priority: i32,
 }
 
 impl SourceBuilder {
@@ -49,6 +120,10 @@ impl SourceBuilder {
             dest,
             urls: vec![url],
             part: None,
+            checksum: None,
+            decompress: None,
+            rate_limit: None,
+            priority: 0,
         }
     }
 
@@ -64,11 +139,49 @@ impl SourceBuilder {
         self
     }
 
+    /// The expected checksum of the source.
+    ///
+    /// If the fetched file does not match it, the file is fetched again from another
+    /// mirror. The fetch fails with a checksum error once every mirror has served a file
+    /// which does not match.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Decompresses the source while it is fetched, storing the decompressed file.
+    pub fn decompress(mut self, decompress: Decompress) -> Self {
+        self.decompress = Some(decompress);
+        self
+    }
+
+    /// Limits the rate at which the source is fetched.
+    ///
+    /// The limiter may be shared with other sources, and adjusted while they are fetched.
+    pub fn rate_limit(mut self, rate_limit: Arc<RateLimiter>) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// The priority of the source, which defaults to 0.
+    ///
+    /// When the fetcher has more sources than it fetches concurrently, waiting sources
+    /// with a higher priority are fetched first.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub fn build(self) -> Source {
         Source {
             urls: Arc::from(self.urls),
             dest: self.dest,
             part: self.part,
+            checksum: self.checksum,
+            decompress: self.decompress,
+            rate_limit: self.rate_limit,
+            priority: self.priority,
+            handle: FetchHandle::default(),
         }
     }
 }
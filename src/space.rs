@@ -0,0 +1,152 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::Error;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Fails with `Error::InsufficientSpace` if `required` bytes can not be written next to `path`.
+///
+/// The check is skipped if the free space of the file system can not be determined.
+pub(crate) fn ensure_space(path: &Path, required: u64) -> Result<(), Error> {
+    match available_space(path) {
+        Ok(available) if available < required => Err(Error::InsufficientSpace(required, available)),
+        Ok(_) => Ok(()),
+        Err(why) => {
+            warn!("unable to check free space for {}: {}", path.display(), why);
+            Ok(())
+        }
+    }
+}
+
+/// Reserves space on the disk for a file of `length` bytes, without changing its length.
+///
+/// Does nothing on file systems and platforms that do not support it.
+pub(crate) fn preallocate(file: &File, length: u64, path: &Path) -> Result<(), Error> {
+    match allocate(file, length) {
+        Ok(()) => Ok(()),
+        Err(why) if is_storage_full(&why) => {
+            let available = available_space(path).unwrap_or(0);
+            Err(Error::InsufficientSpace(length, available))
+        }
+        Err(why) if why.kind() == io::ErrorKind::Unsupported => Ok(()),
+        Err(why) => Err(Error::Write(why)),
+    }
+}
+
+/// The directory whose file system a file will be written to.
+#[cfg(unix)]
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// The bytes available to unprivileged users on the file system of `path`.
+#[cfg(unix)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let directory = CString::new(directory_of(path).as_os_str().as_bytes())
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))?;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `directory` is a valid C string, and `stat` is only read if it was filled.
+    let stat = unsafe {
+        if libc::statvfs(directory.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        stat.assume_init()
+    };
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn allocate(file: &File, length: u64) -> io::Result<()> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+
+    let length = libc::off_t::try_from(length)
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))?;
+
+    // SAFETY: the descriptor is owned by `file`, which outlives the call.
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, length) };
+
+    if result == 0 {
+        return Ok(());
+    }
+
+    let why = io::Error::last_os_error();
+
+    match why.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Err(io::ErrorKind::Unsupported.into()),
+        _ => Err(why),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn allocate(_file: &File, _length: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Whether an error reports that the file system is out of space.
+#[cfg(unix)]
+fn is_storage_full(why: &io::Error) -> bool {
+    why.raw_os_error() == Some(libc::ENOSPC)
+}
+
+#[cfg(not(unix))]
+fn is_storage_full(_why: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn requests_larger_than_the_free_space_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        match ensure_space(&path, u64::MAX) {
+            Err(Error::InsufficientSpace(required, available)) => {
+                assert_eq!(required, u64::MAX);
+                assert!(available < required);
+            }
+            result => panic!(
+                "unexpected result: {:?}",
+                result.map_err(|why| why.to_string())
+            ),
+        }
+    }
+
+    #[test]
+    fn requests_within_the_free_space_succeed() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ensure_space(&dir.path().join("file"), 0).is_ok());
+    }
+
+    #[test]
+    fn preallocating_keeps_the_length_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let file = File::create(&path).unwrap();
+
+        preallocate(&file, 64 * 1024, &path).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,593 @@
+// Copyright 2021-2022 System76 <info@system76.com>
+// SPDX-License-Identifier: MPL-2.0
+
+//! An in-process HTTP backend for testing code built on the `Fetcher`.
+//!
+//! Each URL registered with the `MockBackend` serves a `MockFile`, which honors
+//! `Range` requests the way a well-behaved server would. Misbehavior is scripted by
+//! queueing `Fault`s against a URL, which are consumed in order by the requests that
+//! they match.
+//!
+//! ```
+//! use async_fetcher::testing::{Fault, MockBackend, MockFile};
+//! use async_fetcher::{Fetcher, Source};
+//! use futures::StreamExt;
+//! use http::{Method, StatusCode};
+//! use std::{path::Path, sync::Arc};
+//!
+//! let backend = Arc::new(MockBackend::default());
+//! backend.serve("http://mirror/file", MockFile::new(vec![7u8; 64 * 1024]));
+//!
+//! // The first GET is refused with a server error, which the fetcher retries.
+//! backend.fault(
+//!     "http://mirror/file",
+//!     Some(Method::GET),
+//!     Fault::status(StatusCode::INTERNAL_SERVER_ERROR),
+//! );
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let dest: Arc<Path> = Arc::from(dir.path().join("file"));
+//!
+//! let runtime = tokio::runtime::Builder::new_current_thread()
+//!     .enable_time()
+//!     .build()
+//!     .unwrap();
+//!
+//! let results = runtime.block_on(async {
+//!     let source = Source::builder(dest.clone(), "http://mirror/file".into()).build();
+//!
+//!     Fetcher::new(backend.clone())
+//!         .build()
+//!         .stream_from(futures::stream::iter(vec![(source, Arc::new(()))]), 1)
+//!         .collect::<Vec<_>>()
+//!         .await
+//! });
+//!
+//! assert!(results[0].2.is_ok());
+//! assert_eq!(std::fs::read(&dest).unwrap(), vec![7u8; 64 * 1024]);
+//! assert!(backend.requests().iter().filter(|r| r.method == Method::GET).count() >= 2);
+//! ```
+
+use crate::{Body, Error, HttpBackend};
+use futures::{future::BoxFuture, io::AsyncRead};
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use httpdate::HttpDate;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// A file served by the `MockBackend`.
+#[derive(Clone, Debug, Setters)]
+pub struct MockFile {
+    /// The contents of the file.
+    #[setters(skip)]
+    body: Arc<[u8]>,
+
+    /// Whether `Range` requests are honored.
+    /// # Note
+    /// Defaults to `true`.
+    accept_ranges: bool,
+
+    /// The value of the `Last-Modified` header.
+    #[setters(strip_option)]
+    last_modified: Option<HttpDate>,
+
+    /// Additional headers sent with every response for this file.
+    headers: HeaderMap,
+}
+
+impl MockFile {
+    pub fn new(body: impl Into<Arc<[u8]>>) -> Self {
+        Self {
+            body: body.into(),
+            accept_ranges: true,
+            last_modified: None,
+            headers: HeaderMap::new(),
+        }
+    }
+}
+
+/// Misbehavior to inject into a request to a `MockBackend`.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Responds with the given status and headers, and an empty body.
+    Status(StatusCode, HeaderMap),
+    /// Fails the request as if the connection was refused.
+    ConnectionRefused,
+    /// Waits before sending the response headers.
+    Stall(Duration),
+    /// Pauses the body for `duration` after `after` bytes have been read.
+    StallBody { after: u64, duration: Duration },
+    /// Fails reading the body after `after` bytes have been read.
+    Disconnect { after: u64 },
+    /// Answers a ranged request with a `Content-Range` that starts at the wrong offset.
+    WrongContentRange,
+    /// Answers a ranged request with the entire file.
+    IgnoreRange,
+}
+
+impl Fault {
+    /// Responds with the given status, without any additional headers.
+    pub fn status(status: StatusCode) -> Self {
+        Fault::Status(status, HeaderMap::new())
+    }
+}
+
+/// A request which was received by the `MockBackend`.
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub uri: Box<str>,
+    pub headers: HeaderMap,
+}
+
+#[derive(Default)]
+struct Route {
+    file: Option<MockFile>,
+    faults: VecDeque<(Option<Method>, Fault)>,
+}
+
+/// A scripted HTTP backend which serves files from memory.
+///
+/// Requests for URLs which have not been registered are answered with `404 Not Found`.
+#[derive(Default)]
+pub struct MockBackend {
+    routes: Mutex<HashMap<Box<str>, Route>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+    connections: Arc<Connections>,
+}
+
+/// Counts the connections which are open, from the moment a request is received until
+/// its response body is dropped.
+#[derive(Default)]
+struct Connections {
+    open: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// A connection which is counted as open until it is dropped.
+struct Connection(Arc<Connections>);
+
+impl Connection {
+    fn open(connections: &Arc<Connections>) -> Self {
+        let open = connections.open.fetch_add(1, Ordering::SeqCst) + 1;
+        connections.peak.fetch_max(open, Ordering::SeqCst);
+        Connection(connections.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl MockBackend {
+    /// Serves `file` from `uri`, replacing any file previously served from it.
+    pub fn serve(&self, uri: &str, file: MockFile) {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry(uri.into())
+            .or_default()
+            .file = Some(file);
+    }
+
+    /// Queues a fault for the next request to `uri` with a matching method.
+    ///
+    /// A `method` of `None` matches every request.
+    pub fn fault(&self, uri: &str, method: Option<Method>, fault: Fault) {
+        let mut routes = self.routes.lock().unwrap();
+        let route = routes.entry(uri.into()).or_default();
+        route.faults.push_back((method, fault));
+    }
+
+    /// The number of requests which have been received, and whose response bodies have
+    /// not yet been dropped.
+    pub fn open_connections(&self) -> usize {
+        self.connections.open.load(Ordering::SeqCst)
+    }
+
+    /// The largest number of connections which have been open at the same time.
+    pub fn peak_connections(&self) -> usize {
+        self.connections.peak.load(Ordering::SeqCst)
+    }
+
+    /// All requests which have been received, in the order that they arrived.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn next_fault(&self, uri: &str, method: &Method) -> (Option<MockFile>, Option<Fault>) {
+        let mut routes = self.routes.lock().unwrap();
+
+        let route = match routes.get_mut(uri) {
+            Some(route) => route,
+            None => return (None, None),
+        };
+
+        let position = route
+            .faults
+            .iter()
+            .position(|(filter, _)| filter.is_none() || filter.as_ref() == Some(method));
+
+        let fault = position
+            .and_then(|pos| route.faults.remove(pos))
+            .map(|(_, fault)| fault);
+
+        (route.file.clone(), fault)
+    }
+}
+
+impl HttpBackend for MockBackend {
+    fn send_request(&self, request: Request<()>) -> BoxFuture<'_, Result<Response<Body>, Error>> {
+        let (parts, ()) = request.into_parts();
+        let uri = parts.uri.to_string();
+
+        self.requests.lock().unwrap().push(RecordedRequest {
+            method: parts.method.clone(),
+            uri: uri.as_str().into(),
+            headers: parts.headers.clone(),
+        });
+
+        let (file, fault) = self.next_fault(&uri, &parts.method);
+        let connection = Connection::open(&self.connections);
+
+        Box::pin(async move {
+            let mut body = MockBody {
+                _connection: Some(connection),
+                ..MockBody::default()
+            };
+
+            match fault {
+                Some(Fault::Status(status, headers)) => {
+                    let mut response = Response::new(Box::new(body) as Body);
+                    *response.status_mut() = status;
+                    *response.headers_mut() = headers;
+                    return Ok(response);
+                }
+                Some(Fault::ConnectionRefused) => {
+                    let error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+                    return Err(Error::Backend(Box::new(error)));
+                }
+                Some(Fault::Stall(duration)) => tokio::time::sleep(duration).await,
+                Some(Fault::StallBody { after, duration }) => {
+                    body.stall = Some((after, Box::pin(tokio::time::sleep(duration))))
+                }
+                Some(Fault::Disconnect { after }) => body.disconnect = Some(after),
+                _ => (),
+            }
+
+            let file = match file {
+                Some(file) => file,
+                None => {
+                    let mut response = Response::new(Box::new(body) as Body);
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    return Ok(response);
+                }
+            };
+
+            let total = file.body.len() as u64;
+            let mut status = StatusCode::OK;
+            let mut headers = file.headers.clone();
+            let (mut start, mut end) = (0, total);
+
+            let range = parts
+                .headers
+                .get(header::RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_range);
+
+            if let Some((from, to)) = range.filter(|_| file.accept_ranges) {
+                if !matches!(fault, Some(Fault::IgnoreRange)) {
+                    let to = to.map_or(total, |to| (to + 1).min(total));
+
+                    if from >= to {
+                        let mut response = Response::new(Box::new(body) as Body);
+                        *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                        return Ok(response);
+                    }
+
+                    let reported = if matches!(fault, Some(Fault::WrongContentRange)) {
+                        from + 1
+                    } else {
+                        from
+                    };
+
+                    status = StatusCode::PARTIAL_CONTENT;
+                    start = from;
+                    end = to;
+
+                    headers.insert(
+                        header::CONTENT_RANGE,
+                        header_value(format!("bytes {}-{}/{}", reported, to - 1, total)),
+                    );
+                }
+            }
+
+            headers.insert(header::CONTENT_LENGTH, header_value(end - start));
+
+            if file.accept_ranges {
+                headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            }
+
+            if let Some(modified) = file.last_modified {
+                headers.insert(header::LAST_MODIFIED, header_value(modified));
+            }
+
+            if parts.method != Method::HEAD {
+                body.data = file.body;
+                body.position = start;
+                body.end = end;
+            }
+
+            let mut response = Response::new(Box::new(body) as Body);
+            *response.status_mut() = status;
+            *response.headers_mut() = headers;
+
+            Ok(response)
+        })
+    }
+}
+
+fn header_value(value: impl ToString) -> HeaderValue {
+    HeaderValue::from_str(&value.to_string()).expect("invalid header value")
+}
+
+/// Parses a `bytes=from-to` or `bytes=from-` range.
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (from, to) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let from = from.parse().ok()?;
+    let to = if to.is_empty() {
+        None
+    } else {
+        Some(to.parse().ok()?)
+    };
+
+    Some((from, to))
+}
+
+struct MockBody {
+    data: Arc<[u8]>,
+    position: u64,
+    end: u64,
+    read: u64,
+    disconnect: Option<u64>,
+    stall: Option<(u64, Pin<Box<tokio::time::Sleep>>)>,
+    _connection: Option<Connection>,
+}
+
+impl Default for MockBody {
+    fn default() -> Self {
+        Self {
+            data: Arc::from(Vec::new()),
+            position: 0,
+            end: 0,
+            read: 0,
+            disconnect: None,
+            stall: None,
+            _connection: None,
+        }
+    }
+}
+
+impl AsyncRead for MockBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if let Some((after, sleep)) = this.stall.as_mut() {
+            if this.read >= *after {
+                futures::ready!(sleep.as_mut().poll(cx));
+                this.stall = None;
+            }
+        }
+
+        let mut limit = this.end - this.position;
+
+        if let Some(after) = this.disconnect {
+            if this.read >= after {
+                let error = io::Error::new(io::ErrorKind::ConnectionReset, "disconnected");
+                return Poll::Ready(Err(error));
+            }
+
+            limit = limit.min(after - this.read);
+        }
+
+        if let Some((after, _)) = this.stall.as_ref() {
+            limit = limit.min(after - this.read);
+        }
+
+        let amount = limit.min(buf.len() as u64) as usize;
+        let start = this.position as usize;
+        buf[..amount].copy_from_slice(&this.data[start..start + amount]);
+
+        this.position += amount as u64;
+        this.read += amount as u64;
+
+        Poll::Ready(Ok(amount))
+    }
+}
+
+/// Helpers for the tests of the fetcher itself.
+#[cfg(test)]
+pub(crate) mod support {
+    use super::MockBackend;
+    use crate::{Checksum, Error, FetchReport, Fetcher, RetryPolicy, Source};
+    use futures::StreamExt;
+    use sha2::{Digest, Sha256};
+    use std::future::Future;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Runs a future to completion on a runtime with the features the fetcher needs.
+    ///
+    /// Blocking tasks which are still running afterwards are abandoned, so that a test
+    /// which leaves one waiting fails instead of hanging.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        let output = runtime.block_on(future);
+        runtime.shutdown_timeout(Duration::from_secs(1));
+        output
+    }
+
+    /// The contents of a file whose bytes differ from their neighbours, so that a byte
+    /// which is written at the wrong offset is noticed.
+    pub fn contents(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index * 31 % 251) as u8).collect()
+    }
+
+    /// A fetcher of files from `backend` which retries almost immediately.
+    pub fn fetcher(backend: &Arc<MockBackend>) -> Fetcher<(), Arc<MockBackend>> {
+        let policy = RetryPolicy::default().base_delay(Duration::from_millis(1));
+        Fetcher::new(backend.clone()).retry_policy(policy)
+    }
+
+    /// Fetches the file served from `uris` into `dest`.
+    pub fn fetch(
+        fetcher: Fetcher<(), Arc<MockBackend>>,
+        uris: &[&str],
+        dest: &Path,
+    ) -> Result<FetchReport, Error> {
+        let uris = uris.iter().map(|&uri| Box::from(uri)).collect();
+        block_on(fetcher.build().request(uris, Arc::from(dest), Arc::new(())))
+    }
+
+    /// Fetches the file served from `uris` into `dest`, which must match the checksum
+    /// of `expected`.
+    pub fn fetch_checked(
+        fetcher: Fetcher<(), Arc<MockBackend>>,
+        uris: &[&str],
+        dest: &Path,
+        expected: &[u8],
+    ) -> Result<FetchReport, Error> {
+        let mut source = Source::builder(Arc::from(dest), uris[0].into());
+
+        for &uri in &uris[1..] {
+            source = source.append_url(uri.into());
+        }
+
+        let source = source
+            .checksum(Checksum::Sha256(Sha256::digest(expected)))
+            .build();
+
+        let inputs = futures::stream::iter(vec![(source, Arc::new(()))]);
+        let outputs = fetcher.build().stream_from(inputs, 1);
+        block_on(outputs.collect::<Vec<_>>()).remove(0).2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::support::{self, block_on, contents};
+    use super::*;
+    use futures::io::AsyncReadExt;
+
+    fn send(backend: &MockBackend, method: Method, range: Option<&str>) -> Response<Body> {
+        let mut request = Request::builder().method(method).uri("http://mirror/file");
+
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+
+        block_on(backend.send_request(request.body(()).unwrap())).unwrap()
+    }
+
+    fn read(response: Response<Body>) -> Vec<u8> {
+        let mut body = Vec::new();
+        block_on(response.into_body().read_to_end(&mut body)).unwrap();
+        body
+    }
+
+    #[test]
+    fn ranges() {
+        let backend = MockBackend::default();
+        backend.serve("http://mirror/file", MockFile::new(contents(100)));
+
+        let response = send(&backend, Method::GET, Some("bytes=10-19"));
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 10-19/100");
+        assert_eq!(read(response), &contents(100)[10..20]);
+
+        let response = send(&backend, Method::GET, Some("bytes=100-"));
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        backend.serve(
+            "http://mirror/file",
+            MockFile::new(contents(100)).accept_ranges(false),
+        );
+
+        let response = send(&backend, Method::GET, Some("bytes=10-19"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read(response), contents(100));
+    }
+
+    #[test]
+    fn faults_are_consumed_by_matching_requests() {
+        let backend = MockBackend::default();
+        backend.serve("http://mirror/file", MockFile::new(contents(100)));
+
+        let rejected = Fault::status(StatusCode::METHOD_NOT_ALLOWED);
+        backend.fault("http://mirror/file", Some(Method::HEAD), rejected);
+        backend.fault("http://mirror/file", None, Fault::Disconnect { after: 10 });
+
+        let response = send(&backend, Method::GET, None);
+        let mut body = Vec::new();
+        let result = block_on(response.into_body().read_to_end(&mut body));
+        assert!(result.is_err());
+        assert_eq!(body, &contents(100)[..10]);
+
+        let status = |method| send(&backend, method, None).status();
+        assert_eq!(status(Method::HEAD), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(status(Method::HEAD), StatusCode::OK);
+        assert_eq!(status(Method::GET), StatusCode::OK);
+
+        let methods: Vec<_> = backend.requests().into_iter().map(|r| r.method).collect();
+        assert_eq!(
+            methods,
+            [Method::GET, Method::HEAD, Method::HEAD, Method::GET]
+        );
+    }
+
+    #[test]
+    fn fetch_retries_server_errors() {
+        let backend = Arc::new(MockBackend::default());
+        backend.serve("http://mirror/file", MockFile::new(contents(64 * 1024)));
+
+        let error = Fault::status(StatusCode::INTERNAL_SERVER_ERROR);
+        backend.fault("http://mirror/file", Some(Method::GET), error);
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file");
+
+        let fetcher = support::fetcher(&backend);
+        let report = support::fetch(fetcher, &["http://mirror/file"], &dest).unwrap();
+
+        assert_eq!(report.retries, 1);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents(64 * 1024));
+    }
+
+    #[test]
+    fn unknown_files_are_not_found() {
+        let backend = MockBackend::default();
+        assert_eq!(
+            send(&backend, Method::GET, None).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}